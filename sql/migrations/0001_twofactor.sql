@@ -0,0 +1,12 @@
+-- Two-factor providers enabled for a user (one row per provider type)
+CREATE TABLE IF NOT EXISTS twofactor (
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    type INTEGER NOT NULL, -- Bitwarden TwoFactorProviderType
+    data TEXT NOT NULL, -- Provider specific data, e.g. the TOTP secret
+    last_used INTEGER NOT NULL DEFAULT 0, -- Last accepted TOTP time step, to prevent replay
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    UNIQUE (user_id, type),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN totp_recover TEXT; -- Two-factor recovery code, set once a provider is enabled
+ALTER TABLE devices ADD COLUMN twofactor_remember TEXT; -- Hash of the "remember this device" two-factor token
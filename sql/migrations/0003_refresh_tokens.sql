@@ -0,0 +1,12 @@
+-- Refresh tokens, stored as a SHA-256 hash and bound to the device they were issued to
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    used INTEGER NOT NULL DEFAULT 0, -- Set once the token has been exchanged; reuse revokes the device
+    expires_at INTEGER NOT NULL, -- Unix timestamp
+    created_at TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (device_id) REFERENCES devices(id) ON DELETE CASCADE
+);
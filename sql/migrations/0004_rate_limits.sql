@@ -0,0 +1,7 @@
+-- Attempt counters of the rate limiter, keyed like `login-email:<email>` or `login-ip:<ip>`
+CREATE TABLE IF NOT EXISTS rate_limits (
+    key TEXT PRIMARY KEY NOT NULL,
+    attempts INTEGER NOT NULL,
+    window_start INTEGER NOT NULL, -- Unix timestamp the counting window started at
+    locked_until INTEGER NOT NULL DEFAULT 0 -- Unix timestamp the current lockout ends at
+);
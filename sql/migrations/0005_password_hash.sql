@@ -0,0 +1,4 @@
+-- Rows without a salt hold the client's master password hash as is, and are rehashed on the
+-- next login.
+ALTER TABLE users ADD COLUMN password_salt TEXT; -- Salt of the server side PBKDF2 hash
+ALTER TABLE users ADD COLUMN password_iterations INTEGER NOT NULL DEFAULT 0;
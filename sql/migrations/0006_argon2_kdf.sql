@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN kdf_memory INTEGER; -- Argon2id memory in MiB
+ALTER TABLE users ADD COLUMN kdf_parallelism INTEGER; -- Argon2id parallelism
@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN email_new TEXT; -- Address an email change is pending for
+ALTER TABLE users ADD COLUMN email_new_token TEXT; -- Code sent to that address
+ALTER TABLE users ADD COLUMN email_new_token_sent INTEGER NOT NULL DEFAULT 0; -- Unix timestamp the code was sent at
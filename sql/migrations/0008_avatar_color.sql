@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN avatar_color TEXT; -- Hex color of the avatar, e.g. #175ddc
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN api_key TEXT; -- Client secret of the personal API key, created on first request
@@ -0,0 +1,3 @@
+-- Sessions opened before clients and scopes were recorded came from the interactive clients.
+ALTER TABLE refresh_tokens ADD COLUMN client_id TEXT NOT NULL DEFAULT 'web';
+ALTER TABLE refresh_tokens ADD COLUMN scope TEXT NOT NULL DEFAULT 'api offline_access';
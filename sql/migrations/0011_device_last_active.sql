@@ -0,0 +1 @@
+ALTER TABLE devices ADD COLUMN last_active_at TEXT; -- Last login or token refresh
@@ -0,0 +1,20 @@
+-- Login with device: a new device asks one of the user's signed in devices to approve a login
+CREATE TABLE IF NOT EXISTS auth_requests (
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    type INTEGER NOT NULL, -- 0 to log in and unlock, 1 to unlock only
+    request_device_identifier TEXT NOT NULL,
+    request_device_type INTEGER NOT NULL,
+    request_ip TEXT NOT NULL,
+    public_key TEXT NOT NULL, -- The requesting device's key, the approval encrypts the user key to it
+    access_code_hash TEXT NOT NULL, -- SHA-256 of the code the requesting device proves itself with
+    approved INTEGER, -- NULL while pending, then 1 or 0
+    response_device_identifier TEXT, -- The device that answered
+    key TEXT, -- The user key, encrypted to `public_key`
+    master_password_hash TEXT, -- Encrypted to `public_key` as well, if the approver sent it
+    response_date TEXT,
+    authentication_date TEXT, -- Set when the approval is spent on a login
+    expires_at INTEGER NOT NULL, -- Unix timestamp
+    created_at TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
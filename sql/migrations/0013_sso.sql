@@ -0,0 +1,23 @@
+-- Links an identity at the SSO provider to a user
+CREATE TABLE IF NOT EXISTS sso_users (
+    subject TEXT PRIMARY KEY NOT NULL, -- `sub` claim of the provider's ID tokens
+    user_id TEXT NOT NULL UNIQUE,
+    created_at TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- SSO logins in progress, from the client's authorize request until it redeems the code
+CREATE TABLE IF NOT EXISTS sso_auth (
+    state TEXT PRIMARY KEY NOT NULL, -- Sent to the provider and back
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL, -- Our PKCE verifier towards the provider
+    client_redirect_uri TEXT NOT NULL,
+    client_state TEXT NOT NULL,
+    client_code_challenge TEXT NOT NULL, -- The client's PKCE challenge towards us
+    code_hash TEXT UNIQUE, -- SHA-256 of the code handed to the client once the provider answered
+    subject TEXT,
+    email TEXT,
+    email_verified INTEGER,
+    expires_at INTEGER NOT NULL, -- Unix timestamp
+    created_at TEXT NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Invitations to register, issued by the admin. The invitee holds a signed token naming the row
+CREATE TABLE IF NOT EXISTS invitations (
+    id TEXT PRIMARY KEY NOT NULL,
+    email TEXT NOT NULL,
+    expires_at INTEGER NOT NULL, -- Unix timestamp
+    used_at TEXT, -- Set when an account was registered with it
+    created_at TEXT NOT NULL
+);
@@ -1,4 +1,11 @@
 -- Drop tables if they exist to ensure a clean slate
+DROP TABLE IF EXISTS invitations;
+DROP TABLE IF EXISTS sso_auth;
+DROP TABLE IF EXISTS sso_users;
+DROP TABLE IF EXISTS auth_requests;
+DROP TABLE IF EXISTS rate_limits;
+DROP TABLE IF EXISTS refresh_tokens;
+DROP TABLE IF EXISTS twofactor;
 DROP TABLE IF EXISTS folders;
 DROP TABLE IF EXISTS ciphers;
 DROP TABLE IF EXISTS users;
@@ -9,14 +16,25 @@ CREATE TABLE IF NOT EXISTS users (
     name TEXT,
     email TEXT NOT NULL UNIQUE,
     email_verified BOOLEAN NOT NULL DEFAULT 0,
-    master_password_hash TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT 1, -- 0 while an admin has suspended the account
+    email_new TEXT, -- Address an email change is pending for
+    email_new_token TEXT, -- Code sent to that address
+    email_new_token_sent INTEGER NOT NULL DEFAULT 0, -- Unix timestamp the code was sent at
+    master_password_hash TEXT NOT NULL, -- Server side PBKDF2 of the client hash, base64
+    password_salt TEXT, -- Salt of that hash; NULL for legacy rows holding the client hash as is
+    password_iterations INTEGER NOT NULL DEFAULT 0,
     master_password_hint TEXT,
+    avatar_color TEXT, -- Hex color of the avatar, e.g. #175ddc
     key TEXT NOT NULL, -- The encrypted symmetric key
     private_key TEXT NOT NULL, -- encrypted asymmetric private_key
     public_key TEXT NOT NULL, -- asymmetric public_key
-    kdf_type INTEGER NOT NULL DEFAULT 0, -- 0 for PBKDF2
+    kdf_type INTEGER NOT NULL DEFAULT 0, -- 0 for PBKDF2, 1 for Argon2id
     kdf_iterations INTEGER NOT NULL DEFAULT 600000,
+    kdf_memory INTEGER, -- Argon2id memory in MiB
+    kdf_parallelism INTEGER, -- Argon2id parallelism
     security_stamp TEXT,
+    totp_recover TEXT, -- Two-factor recovery code, set once a provider is enabled
+    api_key TEXT, -- Client secret of the personal API key, created on first request
     created_at TEXT NOT NULL,
     updated_at TEXT NOT NULL
 );
@@ -55,7 +73,99 @@ CREATE TABLE IF NOT EXISTS devices (
     push_token TEXT,
     type INTEGER NOT NULL,
     name TEXT,
+    twofactor_remember TEXT, -- Hash of the "remember this device" two-factor token
+    last_active_at TEXT, -- Last login or token refresh
     created_at TEXT NOT NULL,
     updated_at TEXT NOT NULL,
     FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
 );
+
+-- Refresh tokens, stored as a SHA-256 hash and bound to the device they were issued to
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    client_id TEXT NOT NULL, -- Client the session was opened by, `user.<id>` for the API key
+    scope TEXT NOT NULL, -- Space separated scopes of the access tokens it hands out
+    used INTEGER NOT NULL DEFAULT 0, -- Set once the token has been exchanged; reuse revokes the device
+    expires_at INTEGER NOT NULL, -- Unix timestamp
+    created_at TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+    FOREIGN KEY (device_id) REFERENCES devices(id) ON DELETE CASCADE
+);
+
+-- Attempt counters of the rate limiter, keyed like `login-email:<email>` or `login-ip:<ip>`
+CREATE TABLE IF NOT EXISTS rate_limits (
+    key TEXT PRIMARY KEY NOT NULL,
+    attempts INTEGER NOT NULL,
+    window_start INTEGER NOT NULL, -- Unix timestamp the counting window started at
+    locked_until INTEGER NOT NULL DEFAULT 0 -- Unix timestamp the current lockout ends at
+);
+
+-- Two-factor providers enabled for a user (one row per provider type)
+CREATE TABLE IF NOT EXISTS twofactor (
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    type INTEGER NOT NULL, -- Bitwarden TwoFactorProviderType
+    data TEXT NOT NULL, -- Provider specific data, e.g. the TOTP secret
+    last_used INTEGER NOT NULL DEFAULT 0, -- Last accepted TOTP time step, to prevent replay
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    UNIQUE (user_id, type),
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Login with device: a new device asks one of the user's signed in devices to approve a login
+CREATE TABLE IF NOT EXISTS auth_requests (
+    id TEXT PRIMARY KEY NOT NULL,
+    user_id TEXT NOT NULL,
+    type INTEGER NOT NULL, -- 0 to log in and unlock, 1 to unlock only
+    request_device_identifier TEXT NOT NULL,
+    request_device_type INTEGER NOT NULL,
+    request_ip TEXT NOT NULL,
+    public_key TEXT NOT NULL, -- The requesting device's key, the approval encrypts the user key to it
+    access_code_hash TEXT NOT NULL, -- SHA-256 of the code the requesting device proves itself with
+    approved INTEGER, -- NULL while pending, then 1 or 0
+    response_device_identifier TEXT, -- The device that answered
+    key TEXT, -- The user key, encrypted to `public_key`
+    master_password_hash TEXT, -- Encrypted to `public_key` as well, if the approver sent it
+    response_date TEXT,
+    authentication_date TEXT, -- Set when the approval is spent on a login
+    expires_at INTEGER NOT NULL, -- Unix timestamp
+    created_at TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Links an identity at the SSO provider to a user
+CREATE TABLE IF NOT EXISTS sso_users (
+    subject TEXT PRIMARY KEY NOT NULL, -- `sub` claim of the provider's ID tokens
+    user_id TEXT NOT NULL UNIQUE,
+    created_at TEXT NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- SSO logins in progress, from the client's authorize request until it redeems the code
+CREATE TABLE IF NOT EXISTS sso_auth (
+    state TEXT PRIMARY KEY NOT NULL, -- Sent to the provider and back
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL, -- Our PKCE verifier towards the provider
+    client_redirect_uri TEXT NOT NULL,
+    client_state TEXT NOT NULL,
+    client_code_challenge TEXT NOT NULL, -- The client's PKCE challenge towards us
+    code_hash TEXT UNIQUE, -- SHA-256 of the code handed to the client once the provider answered
+    subject TEXT,
+    email TEXT,
+    email_verified INTEGER,
+    expires_at INTEGER NOT NULL, -- Unix timestamp
+    created_at TEXT NOT NULL
+);
+
+-- Invitations to register, issued by the admin. The invitee holds a signed token naming the row
+CREATE TABLE IF NOT EXISTS invitations (
+    id TEXT PRIMARY KEY NOT NULL,
+    email TEXT NOT NULL,
+    expires_at INTEGER NOT NULL, -- Unix timestamp
+    used_at TEXT, -- Set when an account was registered with it
+    created_at TEXT NOT NULL
+);
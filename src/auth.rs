@@ -2,12 +2,29 @@ use axum::{
     extract::FromRequestParts,
     http::{header, request::Parts},
 };
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use chrono::{Duration, Utc};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
-use worker::Env;
+use std::{collections::HashMap, sync::Arc, sync::Mutex};
+use uuid::Uuid;
+use worker::{query, send::SendFuture, D1Database, Env};
 
-use crate::error::AppError;
+use crate::{db, error::AppError, jwt, models::user::bool_from_int, refresh_token};
+
+// How long a user's security stamp, enabled flag and devices are trusted without reading them
+// again. Other isolates only notice a change after this long, the one that made it notices at
+// once.
+const STAMP_CACHE_SECONDS: i64 = 30;
+const STAMP_CACHE_MAX_ENTRIES: usize = 1024;
+
+struct CachedStamp {
+    row: StampRow,
+    fetched_at: i64,
+}
+
+// Keyed by user ID and device identifier.
+static STAMP_CACHE: Lazy<Mutex<HashMap<(String, String), CachedStamp>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
@@ -20,6 +37,222 @@ pub struct Claims {
     pub email: String,
     pub email_verified: bool,
     pub amr: Vec<String>,
+    pub sstamp: String, // Security stamp of the user when the token was issued
+
+    pub scope: Vec<String>,
+    pub client_id: String,  // The client that logged in, `user.<id>` for the API key
+    pub device: String,     // Identifier of the device the session belongs to
+    pub token_type: String, // ACCESS_TOKEN, telling it apart from the other tokens we sign
+}
+
+pub const ACCESS_TOKEN: &str = "access";
+const ACTION_TOKEN: &str = "action";
+
+pub const SCOPE_API: &str = "api";
+pub const SCOPE_OFFLINE_ACCESS: &str = "offline_access";
+
+impl Claims {
+    /// Whether the session was opened with the personal API key rather than the master password.
+    pub fn is_api_key(&self) -> bool {
+        self.client_id.starts_with("user.")
+    }
+}
+
+/// What a route asks of the access token it is called with. Routes declare it with
+/// `.route_layer(Extension(..))`, the ones that don't get [`TokenRequirement::API`].
+#[derive(Debug, Clone, Copy)]
+pub struct TokenRequirement {
+    pub scope: &'static str,
+    pub allow_api_key: bool,
+}
+
+impl TokenRequirement {
+    /// The vault and everything else the clients do day to day.
+    pub const API: Self = TokenRequirement {
+        scope: SCOPE_API,
+        allow_api_key: true,
+    };
+    /// Account security settings, which need the master password session of the owner.
+    pub const INTERACTIVE: Self = TokenRequirement {
+        scope: SCOPE_API,
+        allow_api_key: false,
+    };
+}
+
+#[derive(Clone, Deserialize)]
+struct StampRow {
+    security_stamp: String,
+    #[serde(deserialize_with = "bool_from_int::deserialize")]
+    enabled: bool,
+    // Whether the device of the session is still one of the user's devices
+    #[serde(deserialize_with = "bool_from_int::deserialize")]
+    device_exists: bool,
+}
+
+/// Returns the user's current security stamp, whether the account is enabled and whether the
+/// device is still registered to it, or `None` if the user no longer exists.
+async fn current_security_stamp(
+    db: &D1Database,
+    user_id: &str,
+    device: &str,
+) -> Result<Option<StampRow>, AppError> {
+    let now = Utc::now().timestamp();
+    let key = (user_id.to_string(), device.to_string());
+    if let Some(cached) = STAMP_CACHE.lock().unwrap().get(&key) {
+        if now - cached.fetched_at < STAMP_CACHE_SECONDS {
+            return Ok(Some(cached.row.clone()));
+        }
+    }
+
+    let row: Option<StampRow> = query!(
+        db,
+        "SELECT security_stamp, enabled,
+            EXISTS (SELECT 1 FROM devices WHERE devices.user_id = users.id AND devices.identifier = ?2) AS device_exists
+         FROM users WHERE id = ?1",
+        user_id,
+        device
+    )
+    .map_err(|_| AppError::Database)?
+    .first(None)
+    .await
+    .map_err(|_| AppError::Database)?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let mut cache = STAMP_CACHE.lock().unwrap();
+    if cache.len() >= STAMP_CACHE_MAX_ENTRIES {
+        cache.retain(|_, cached| now - cached.fetched_at < STAMP_CACHE_SECONDS);
+    }
+    cache.insert(
+        key,
+        CachedStamp {
+            row: row.clone(),
+            fetched_at: now,
+        },
+    );
+
+    Ok(Some(row))
+}
+
+/// Drops the cached stamps of a user whose stamp, enabled flag or devices were just changed.
+pub fn forget_security_stamp(user_id: &str) {
+    STAMP_CACHE
+        .lock()
+        .unwrap()
+        .retain(|(cached_user_id, _), _| cached_user_id != user_id);
+}
+
+/// The error for an account an admin has disabled.
+pub fn account_disabled() -> AppError {
+    AppError::Unauthorized("This account has been disabled".to_string())
+}
+
+pub fn new_security_stamp() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Gives the user a new security stamp, which ends every session: access tokens stop
+/// validating, refresh tokens are revoked and remembered two-factor devices are forgotten.
+pub async fn rotate_security_stamp(db: &D1Database, user_id: &str) -> Result<(), AppError> {
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    db.batch(vec![
+        query!(
+            db,
+            "UPDATE users SET security_stamp = ?1, updated_at = ?2 WHERE id = ?3",
+            new_security_stamp(),
+            now,
+            user_id
+        )
+        .map_err(|_| AppError::Database)?,
+        refresh_token::revoke_user(db, user_id)?,
+    ])
+    .await?;
+    forget_security_stamp(user_id);
+
+    Ok(())
+}
+
+/// Claims of a token mailed to a user to confirm a single action, such as deleting the account.
+#[derive(Debug, Serialize, Deserialize)]
+struct ActionClaims {
+    sub: String, // User ID
+    exp: usize,
+    nbf: usize,
+    act: String, // The action the token confirms
+    // A value of the account the token only works with while it stays the same
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bound: Option<String>,
+    token_type: String,
+}
+
+fn sign_action_token(
+    env: &Env,
+    user_id: &str,
+    action: &str,
+    bound: Option<&str>,
+    valid_for: Duration,
+) -> Result<String, AppError> {
+    let now = Utc::now();
+    let claims = ActionClaims {
+        sub: user_id.to_string(),
+        exp: (now + valid_for).timestamp() as usize,
+        nbf: now.timestamp() as usize,
+        act: action.to_string(),
+        bound: bound.map(str::to_string),
+        token_type: ACTION_TOKEN.to_string(),
+    };
+    jwt::sign(env, &claims)
+}
+
+fn verify_action_token(env: &Env, token: &str, action: &str) -> Result<ActionClaims, AppError> {
+    let claims: ActionClaims = jwt::verify(env, token).map_err(|_| invalid_action_token())?;
+    if claims.token_type != ACTION_TOKEN || claims.act != action {
+        return Err(invalid_action_token());
+    }
+    Ok(claims)
+}
+
+fn invalid_action_token() -> AppError {
+    AppError::BadRequest("Invalid or expired token".to_string())
+}
+
+pub fn encode_action_token(
+    env: &Env,
+    user_id: &str,
+    action: &str,
+    valid_for: Duration,
+) -> Result<String, AppError> {
+    sign_action_token(env, user_id, action, None, valid_for)
+}
+
+/// Checks a token from [`encode_action_token`], returning the user it was issued to.
+pub fn decode_action_token(env: &Env, token: &str, action: &str) -> Result<String, AppError> {
+    Ok(verify_action_token(env, token, action)?.sub)
+}
+
+/// Like [`encode_action_token`], for a token that stops working once `bound`, e.g. the
+/// account's email or security stamp, changes.
+pub fn encode_bound_action_token(
+    env: &Env,
+    user_id: &str,
+    action: &str,
+    bound: &str,
+    valid_for: Duration,
+) -> Result<String, AppError> {
+    sign_action_token(env, user_id, action, Some(bound), valid_for)
+}
+
+/// Checks a token from [`encode_bound_action_token`], returning the user it was issued to and
+/// the value it is bound to, for the caller to compare with the current one.
+pub fn decode_bound_action_token(
+    env: &Env,
+    token: &str,
+    action: &str,
+) -> Result<(String, String), AppError> {
+    let claims = verify_action_token(env, token, action)?;
+    let bound = claims.bound.ok_or_else(invalid_action_token)?;
+    Ok((claims.sub, bound))
 }
 
 impl FromRequestParts<Arc<Env>> for Claims
@@ -32,22 +265,43 @@ impl FromRequestParts<Arc<Env>> for Claims
             .headers
             .get(header::AUTHORIZATION)
             .and_then(|auth_header| auth_header.to_str().ok())
-            .and_then(|auth_value| {
-                if auth_value.starts_with("Bearer ") {
-                    Some(auth_value[7..].to_owned())
-                } else {
-                    None
-                }
-            })
+            .and_then(|auth_value| auth_value.strip_prefix("Bearer "))
             .ok_or_else(|| AppError::Unauthorized("Missing or invalid token".to_string()))?;
 
-        let secret = state.secret("JWT_SECRET")?;
-
         // Decode and validate the token
-        let decoding_key = DecodingKey::from_secret(secret.to_string().as_ref());
-        let token_data = decode::<Claims>(&token, &decoding_key, &Validation::default())
+        let claims: Claims = jwt::verify(state, token)
             .map_err(|_| AppError::Unauthorized("Invalid token".to_string()))?;
+        if claims.token_type != ACCESS_TOKEN {
+            return Err(AppError::Unauthorized("Invalid token".to_string()));
+        }
+
+        let requirement = parts
+            .extensions
+            .get::<TokenRequirement>()
+            .copied()
+            .unwrap_or(TokenRequirement::API);
+        if !claims.scope.iter().any(|scope| scope == requirement.scope)
+            || (claims.is_api_key() && !requirement.allow_api_key)
+        {
+            return Err(AppError::Unauthorized(
+                "Token not allowed for this endpoint".to_string(),
+            ));
+        }
+
+        // Tokens issued before the stamp last changed, or for a device that was since removed,
+        // belong to sessions that were ended.
+        // D1 futures aren't `Send`, which axum asks of extractors. Workers are single threaded.
+        let current = SendFuture::new(async {
+            let db = db::get_db(state)?;
+            current_security_stamp(&db, &claims.sub, &claims.device).await
+        })
+        .await?
+        .filter(|current| current.security_stamp == claims.sstamp && current.device_exists)
+        .ok_or_else(|| AppError::Unauthorized("Invalid token".to_string()))?;
+        if !current.enabled {
+            return Err(account_disabled());
+        }
 
-        Ok(token_data.claims)
+        Ok(claims)
     }
 }
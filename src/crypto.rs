@@ -1,3 +1,5 @@
+use base64::{engine::general_purpose, Engine as _};
+use constant_time_eq::constant_time_eq;
 use js_sys::Uint8Array;
 use wasm_bindgen::JsValue;
 use wasm_bindgen_futures::JsFuture;
@@ -72,10 +74,61 @@ pub async fn pbkdf2_sha256(
     Ok(js_sys::Uint8Array::new(&derived_bits).to_vec())
 }
 
-/// Generates a hash of the master key for password verification.
-pub async fn hash_master_key(
-    master_key: &[u8],
-    master_password: &[u8],
+/// Iterations of the server side hash. Workers' WebCrypto refuses more than 100k.
+pub const PASSWORD_ITERATIONS: u32 = 100_000;
+
+/// The server side hash of the master password hash a client sends, as stored on the user.
+pub struct PasswordHash {
+    pub hash: String,
+    pub salt: String,
+    pub iterations: u32,
+}
+
+async fn derive_password_hash(
+    master_password_hash: &str,
+    salt: &[u8],
+    iterations: u32,
 ) -> Result<Vec<u8>, AppError> {
-    pbkdf2_sha256(master_key, master_password, 1, 256).await
+    pbkdf2_sha256(master_password_hash.as_bytes(), salt, iterations, 256).await
+}
+
+/// Hashes a client master password hash with a new random salt.
+pub async fn hash_password(master_password_hash: &str) -> Result<PasswordHash, AppError> {
+    let salt = rand::random::<[u8; 16]>();
+    let hash = derive_password_hash(master_password_hash, &salt, PASSWORD_ITERATIONS).await?;
+
+    Ok(PasswordHash {
+        hash: general_purpose::STANDARD.encode(hash),
+        salt: general_purpose::STANDARD.encode(salt),
+        iterations: PASSWORD_ITERATIONS,
+    })
+}
+
+/// Checks a client master password hash against a stored [`PasswordHash`].
+pub async fn verify_password(
+    master_password_hash: &str,
+    stored: &PasswordHash,
+) -> Result<bool, AppError> {
+    let salt = general_purpose::STANDARD
+        .decode(&stored.salt)
+        .map_err(|_| AppError::Internal)?;
+    let expected = general_purpose::STANDARD
+        .decode(&stored.hash)
+        .map_err(|_| AppError::Internal)?;
+    let hash = derive_password_hash(master_password_hash, &salt, stored.iterations).await?;
+
+    Ok(constant_time_eq(&hash, &expected))
+}
+
+pub fn base64url_encode(bytes: &[u8]) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Decodes the base64url (or padded standard base64) strings sent by the clients.
+pub fn base64url_decode(input: &str) -> Result<Vec<u8>, AppError> {
+    let trimmed = input.trim_end_matches('=');
+    general_purpose::URL_SAFE_NO_PAD
+        .decode(trimmed)
+        .or_else(|_| general_purpose::STANDARD_NO_PAD.decode(trimmed))
+        .map_err(|_| AppError::BadRequest("Invalid base64".to_string()))
 }
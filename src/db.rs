@@ -1,7 +1,47 @@
 use crate::error::AppError;
+use crate::models::user::User;
 use std::sync::Arc;
-use worker::{D1Database, Env};
+use worker::{query, D1Database, Env};
 
 pub fn get_db(env: &Arc<Env>) -> Result<D1Database, AppError> {
     env.d1("vault1").map_err(AppError::Worker)
 }
+
+pub async fn get_user(db: &D1Database, user_id: &str) -> Result<User, AppError> {
+    query!(db, "SELECT * FROM users WHERE id = ?1", user_id)
+        .map_err(|_| AppError::Database)?
+        .first(None)
+        .await
+        .map_err(|_| AppError::Database)?
+        .ok_or_else(|| AppError::NotFound("User not found".to_string()))
+}
+
+/// Tables holding data of a single user, in the order their rows are deleted. Anything new
+/// with a `user_id` column belongs here, so deleting an account doesn't leave it behind.
+const USER_TABLES: &[&str] = &[
+    "auth_requests",
+    "sso_users",
+    "refresh_tokens",
+    "twofactor",
+    "ciphers",
+    "folders",
+    "devices",
+];
+
+/// Deletes a user and everything they own in one batch. This doesn't lean on
+/// `ON DELETE CASCADE`, which only works where D1 enforces foreign keys.
+pub async fn delete_user(db: &D1Database, user_id: &str) -> Result<(), AppError> {
+    let mut statements = Vec::with_capacity(USER_TABLES.len() + 1);
+    for table in USER_TABLES {
+        statements.push(
+            db.prepare(format!("DELETE FROM {table} WHERE user_id = ?1"))
+                .bind(&[user_id.into()])?,
+        );
+    }
+    statements.push(
+        query!(db, "DELETE FROM users WHERE id = ?1", user_id).map_err(|_| AppError::Database)?,
+    );
+    db.batch(statements).await?;
+
+    Ok(())
+}
@@ -1,7 +1,7 @@
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
-use serde_json::json;
+use serde_json::{json, Value};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -27,6 +27,12 @@ pub enum AppError {
     #[error(transparent)]
     JsonWebToken(#[from] jsonwebtoken::errors::Error),
 
+    #[error("Two factor required")]
+    TwoFactorRequired(Value),
+
+    #[error("Too many requests, retry in {0} seconds")]
+    TooManyRequests(i64),
+
     #[error("Internal server error")]
     Internal,
 }
@@ -50,6 +56,32 @@ impl IntoResponse for AppError {
                 format!("Crypto error: {}", msg),
             ),
             AppError::JsonWebToken(_) => (StatusCode::UNAUTHORIZED, "Invalid token".to_string()),
+            // The login challenge has a fixed shape the clients parse, so it is sent as is.
+            AppError::TwoFactorRequired(body) => {
+                return (StatusCode::BAD_REQUEST, Json(body)).into_response()
+            }
+            AppError::TooManyRequests(retry_after) => {
+                let minutes = (retry_after + 59) / 60;
+                let message = format!(
+                    "Too many attempts. Try again in {minutes} minute{}.",
+                    if minutes == 1 { "" } else { "s" }
+                );
+                // Identity clients read `ErrorModel`, the others `message`.
+                let body = json!({
+                    "error": "too_many_requests",
+                    "error_description": message,
+                    "ErrorModel": { "Message": message, "Object": "error" },
+                    "message": message,
+                    "validationErrors": null,
+                    "object": "error",
+                });
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(header::RETRY_AFTER, retry_after.to_string())],
+                    Json(body),
+                )
+                    .into_response();
+            }
             AppError::Internal => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Internal server error".to_string(),
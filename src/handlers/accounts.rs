@@ -1,36 +1,81 @@
-use axum::{extract::State, Json};
-use chrono::Utc;
+use axum::{extract::State, http::HeaderMap, Json};
+use chrono::{Duration, Utc};
+use constant_time_eq::constant_time_eq;
+use rand::Rng;
+use serde::Deserialize;
 use serde_json::{json, Value};
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 use uuid::Uuid;
-use worker::{query, Env};
+use worker::{js_sys, query, D1Database, Env};
 
 use crate::{
+    auth::{self, Claims},
+    crypto,
     db,
     error::AppError,
-    models::user::{PreloginResponse, RegisterRequest, User},
+    handlers::{config, sync},
+    mail::{Email, Mailer},
+    models::{
+        cipher::CipherData,
+        sync::Profile,
+        two_factor::PasswordData,
+        user::{
+            ApiKeyResponse, ChangeEmailRequest, ChangeKdfRequest, ChangePasswordRequest,
+            DeleteRecoverRequest, DeleteRecoverTokenRequest, EmailTokenRequest, KdfParams,
+            KeyRotationRequest, PreloginResponse, RegisterRequest, RotatedCipher, RotatedFolder,
+            SendVerificationEmailRequest, UpdateAvatarRequest, UpdateProfileRequest, User,
+            VerificationEmailClickedRequest, VerifyEmailTokenRequest, KDF_PBKDF2,
+        },
+    },
+    rate_limit::{self, RateLimiter, SystemClock, LOOKUP_BY_IP},
+    refresh_token, signup,
 };
 
+#[derive(Deserialize)]
+struct KdfRow {
+    kdf_type: i32,
+    kdf_iterations: i32,
+    kdf_memory: Option<i32>,
+    kdf_parallelism: Option<i32>,
+}
+
 #[worker::send]
 pub async fn prelogin(
     State(env): State<Arc<Env>>,
+    headers: HeaderMap,
     Json(payload): Json<serde_json::Value>,
 ) -> Result<Json<PreloginResponse>, AppError> {
     let email = payload["email"]
         .as_str()
         .ok_or_else(|| AppError::BadRequest("Missing email".to_string()))?;
     let db = db::get_db(&env)?;
+    RateLimiter::new(&db, SystemClock)
+        .attempt(&LOOKUP_BY_IP, &rate_limit::client_ip(&headers))
+        .await?;
 
-    let stmt = db.prepare("SELECT kdf_iterations FROM users WHERE email = ?1");
-    let query = stmt.bind(&[email.into()])?;
-    let kdf_iterations: Option<i32> = query
-        .first(Some("kdf_iterations"))
-        .await
-        .map_err(|_| AppError::Database)?;
+    let kdf: Option<KdfRow> = query!(
+        &db,
+        "SELECT kdf_type, kdf_iterations, kdf_memory, kdf_parallelism FROM users WHERE email = ?1",
+        email.to_lowercase()
+    )
+    .map_err(|_| AppError::Database)?
+    .first(None)
+    .await
+    .map_err(|_| AppError::Database)?;
+
+    // Unknown accounts get the defaults, so the response doesn't tell whether one exists.
+    let kdf = kdf.unwrap_or(KdfRow {
+        kdf_type: KDF_PBKDF2,
+        kdf_iterations: 600_000,
+        kdf_memory: None,
+        kdf_parallelism: None,
+    });
 
     Ok(Json(PreloginResponse {
-        kdf: 0, // PBKDF2
-        kdf_iterations: kdf_iterations.unwrap_or(600_000),
+        kdf: kdf.kdf_type,
+        kdf_iterations: kdf.kdf_iterations,
+        kdf_memory: kdf.kdf_memory,
+        kdf_parallelism: kdf.kdf_parallelism,
     }))
 }
 
@@ -39,42 +84,74 @@ pub async fn register(
     State(env): State<Arc<Env>>,
     Json(payload): Json<RegisterRequest>,
 ) -> Result<Json<Value>, AppError> {
-    let allowed_emails = env
-        .secret("ALLOWED_EMAILS")
-        .map_err(|_| AppError::Internal)?;
-    let allowed_emails = allowed_emails
-        .as_ref()
-        .as_string()
-        .ok_or_else(|| AppError::Internal)?;
-    if allowed_emails
-        .split(",")
-        .all(|email| email.trim() != payload.email)
-    {
-        return Err(AppError::Unauthorized("Not allowed to signup".to_string()));
+    let kdf = KdfParams {
+        kdf_type: payload.kdf,
+        iterations: payload.kdf_iterations,
+        memory: payload.kdf_memory,
+        parallelism: payload.kdf_parallelism,
     }
+    .validate()?;
+
     let db = db::get_db(&env)?;
+    let email = payload.email.to_lowercase();
+    let token = payload
+        .org_invite_token
+        .as_deref()
+        .or(payload.email_verification_token.as_deref());
+    let (invitation, email_verified) = match token.and_then(|t| signup_token(&env, t)) {
+        Some((token_email, mailed)) => {
+            if token_email != email {
+                return Err(AppError::BadRequest("Invalid or expired token".to_string()));
+            }
+            (signup::check(&env, &db, &email, None).await?, mailed)
+        }
+        None => {
+            // With mail set up, only invitees may skip verifying their address.
+            if token.is_none() && Mailer::from_env(&env)?.is_some() {
+                return Err(AppError::BadRequest(
+                    "Verify your email address before registering".to_string(),
+                ));
+            }
+            (signup::check(&env, &db, &email, token).await?, false)
+        }
+    };
+
     let now = Utc::now().to_rfc3339();
+    let password = crypto::hash_password(&payload.master_password_hash).await?;
     let user = User {
         id: Uuid::new_v4().to_string(),
         name: payload.name,
-        email: payload.email.to_lowercase(),
-        email_verified: false,
-        master_password_hash: payload.master_password_hash,
+        email,
+        email_verified,
+        enabled: true,
+        email_new: None,
+        email_new_token: None,
+        email_new_token_sent: 0,
+        master_password_hash: password.hash,
+        password_salt: Some(password.salt),
+        password_iterations: password.iterations as i32,
         master_password_hint: payload.master_password_hint,
+        avatar_color: None,
         key: payload.user_symmetric_key,
         private_key: payload.user_asymmetric_keys.encrypted_private_key,
         public_key: payload.user_asymmetric_keys.public_key,
-        kdf_type: payload.kdf,
-        kdf_iterations: payload.kdf_iterations,
-        security_stamp: Uuid::new_v4().to_string(),
+        kdf_type: kdf.kdf_type,
+        kdf_iterations: kdf.iterations,
+        kdf_memory: kdf.memory,
+        kdf_parallelism: kdf.parallelism,
+        security_stamp: auth::new_security_stamp(),
+        totp_recover: None,
+        api_key: None,
         created_at: now.clone(),
         updated_at: now,
     };
 
-    let _query = query!(
+    // Only while there is room, counted as the account is inserted.
+    let insert = query!(
         &db,
-        "INSERT INTO users (id, name, email, master_password_hash, key, private_key, public_key, kdf_iterations, security_stamp, created_at, updated_at)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        "INSERT INTO users (id, name, email, master_password_hash, key, private_key, public_key, kdf_iterations, security_stamp, created_at, updated_at, password_salt, password_iterations, kdf_type, kdf_memory, kdf_parallelism, email_verified)
+         SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17
+         WHERE ?18 IS NULL OR (SELECT COUNT(*) FROM users) < ?18",
          user.id,
          user.name,
          user.email,
@@ -85,22 +162,216 @@ pub async fn register(
          user.kdf_iterations,
          user.security_stamp,
          user.created_at,
-         user.updated_at
+         user.updated_at,
+         user.password_salt,
+         user.password_iterations,
+         user.kdf_type,
+         user.kdf_memory,
+         user.kdf_parallelism,
+         user.email_verified as i32,
+         signup::max_users(&env)
     ).map_err(|_error|{
         AppError::Database
-    })?
-    .run()
-    .await
-    .map_err(|_error|{
-        AppError::Database
     })?;
+    if let Some(invitation) = &invitation {
+        signup::spend(&db, invitation).await?;
+    }
+    let changes = insert
+        .run()
+        .await
+        .and_then(|result| result.meta())
+        .map(|meta| meta.and_then(|meta| meta.changes).unwrap_or(0));
+    if !matches!(changes, Ok(1)) {
+        if let Some(invitation) = &invitation {
+            signup::release(&db, invitation).await?;
+        }
+        return Err(match changes {
+            Ok(_) => signup::not_allowed(),
+            Err(_) => AppError::Database,
+        });
+    }
 
     Ok(Json(json!({})))
 }
 
+// A signup token mailed to the address, which proves it, or handed out when mail is disabled.
+const SIGNUP_VERIFIED_ACTION: &str = "verify_signup";
+const SIGNUP_ACTION: &str = "signup";
+const VERIFY_EMAIL_ACTION: &str = "verify_email";
+// How long the links of verification emails work.
+const VERIFY_EMAIL_TOKEN_HOURS: i64 = 24;
+
+/// Checks a token from [`send_verification_email`], returning the address it was issued for
+/// and whether it was mailed there.
+fn signup_token(env: &Env, token: &str) -> Option<(String, bool)> {
+    if let Ok(email) = auth::decode_action_token(env, token, SIGNUP_VERIFIED_ACTION) {
+        return Some((email, true));
+    }
+    auth::decode_action_token(env, token, SIGNUP_ACTION)
+        .ok()
+        .map(|email| (email, false))
+}
+
+async fn email_registered(db: &D1Database, email: &str) -> Result<bool, AppError> {
+    let user: Option<Value> = query!(db, "SELECT id FROM users WHERE email = ?1", email)
+        .map_err(|_| AppError::Database)?
+        .first(None)
+        .await
+        .map_err(|_| AppError::Database)?;
+    Ok(user.is_some())
+}
+
+/// Starts registration. With mail set up, the link to finish it is mailed to the address and
+/// the reply is empty, whether or not the address already has an account. Without mail, the
+/// reply is the token to finish with.
+#[worker::send]
+pub async fn send_verification_email(
+    State(env): State<Arc<Env>>,
+    headers: HeaderMap,
+    Json(payload): Json<SendVerificationEmailRequest>,
+) -> Result<Json<Option<String>>, AppError> {
+    let email = payload.email.trim().to_lowercase();
+    if !email.contains('@') {
+        return Err(AppError::BadRequest("Invalid email address".to_string()));
+    }
+
+    let db = db::get_db(&env)?;
+    RateLimiter::new(&db, SystemClock)
+        .attempt(&LOOKUP_BY_IP, &rate_limit::client_ip(&headers))
+        .await?;
+    signup::check(&env, &db, &email, None).await?;
+
+    let valid_for = Duration::hours(VERIFY_EMAIL_TOKEN_HOURS);
+    let Some(mailer) = Mailer::from_env(&env)? else {
+        let token = auth::encode_action_token(&env, &email, SIGNUP_ACTION, valid_for)?;
+        return Ok(Json(Some(token)));
+    };
+    if email_registered(&db, &email).await? {
+        return Ok(Json(None));
+    }
+
+    let token = auth::encode_action_token(&env, &email, SIGNUP_VERIFIED_ACTION, valid_for)?;
+    let query = serde_urlencoded::to_string([
+        ("token", token.as_str()),
+        ("email", email.as_str()),
+        ("fromEmail", "true"),
+    ])
+    .map_err(|_| AppError::Internal)?;
+    let link = format!("{}/#/finish-signup?{query}", config::domain(&env));
+    let greeting = payload
+        .name
+        .filter(|name| !name.trim().is_empty())
+        .map_or_else(|| "Hello,".to_string(), |name| format!("Hello {},", name.trim()));
+    let message = Email {
+        to: email,
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "{greeting}\n\nOpen this link to verify your email address and finish creating \
+             your account:\n\n{link}\n\n\
+             The link expires in {VERIFY_EMAIL_TOKEN_HOURS} hours. If you did not ask for this, \
+             you can ignore this email."
+        ),
+    };
+    mailer.send(&message).await?;
+
+    Ok(Json(None))
+}
+
+/// Called by the web vault when the link of [`send_verification_email`] is opened, before it
+/// asks for the master password.
+#[worker::send]
+pub async fn verification_email_clicked(
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<VerificationEmailClickedRequest>,
+) -> Result<Json<()>, AppError> {
+    let email = payload.email.trim().to_lowercase();
+    match signup_token(&env, &payload.email_verification_token) {
+        Some((token_email, _)) if token_email == email => {}
+        _ => return Err(AppError::BadRequest("Invalid or expired token".to_string())),
+    }
+
+    let db = db::get_db(&env)?;
+    if email_registered(&db, &email).await? {
+        return Err(AppError::BadRequest(
+            "This email address is already registered".to_string(),
+        ));
+    }
+
+    Ok(Json(()))
+}
+
+/// Mails a link that verifies the address of the account.
 #[worker::send]
-pub async fn send_verification_email() -> String {
-    "fixed-token-to-mock".to_string()
+pub async fn verify_email(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    if user.email_verified {
+        return Err(AppError::BadRequest("Email is already verified".to_string()));
+    }
+    let mailer = Mailer::required(&env)?;
+
+    // Bound to the address, so the link can't verify one the account changed to since.
+    let token = auth::encode_bound_action_token(
+        &env,
+        &user.id,
+        VERIFY_EMAIL_ACTION,
+        &user.email,
+        Duration::hours(VERIFY_EMAIL_TOKEN_HOURS),
+    )?;
+    let link = format!(
+        "{}/#/verify-email?userId={}&token={}",
+        config::domain(&env),
+        user.id,
+        token
+    );
+    let email = Email {
+        to: user.email,
+        subject: "Verify your email address".to_string(),
+        body: format!(
+            "Open this link to verify the email address of your account:\n\n{link}\n\n\
+             The link expires in {VERIFY_EMAIL_TOKEN_HOURS} hours. If you did not ask for this, \
+             you can ignore this email."
+        ),
+    };
+    mailer.send(&email).await?;
+
+    Ok(Json(()))
+}
+
+/// Verifies the address of the account with the link from [`verify_email`].
+#[worker::send]
+pub async fn verify_email_token(
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<VerifyEmailTokenRequest>,
+) -> Result<Json<()>, AppError> {
+    let invalid = || AppError::BadRequest("Invalid or expired token".to_string());
+    let (user_id, email) =
+        auth::decode_bound_action_token(&env, &payload.token, VERIFY_EMAIL_ACTION)?;
+    if user_id != payload.user_id {
+        return Err(invalid());
+    }
+
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &user_id).await?;
+    if user.email != email {
+        return Err(invalid());
+    }
+    // Conditional on the address too, in case it changes while this runs.
+    query!(
+        &db,
+        "UPDATE users SET email_verified = 1, updated_at = ?1 WHERE id = ?2 AND email = ?3",
+        Utc::now().to_rfc3339(),
+        user.id,
+        email
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok(Json(()))
 }
 
 #[worker::send]
@@ -128,3 +399,623 @@ pub async fn get_revision_date(
 
     Ok(Json(updated_at))
 }
+
+/// Ends every session of the user, including this one ("Deauthorize sessions").
+#[worker::send]
+pub async fn rotate_security_stamp(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<PasswordData>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    if !user.check_master_password(&payload.master_password_hash).await? {
+        return Err(AppError::BadRequest("Invalid password".to_string()));
+    }
+
+    auth::rotate_security_stamp(&db, &user.id).await?;
+
+    Ok(Json(()))
+}
+
+/// Changes the master password. The vault stays as it is, only the user key is re-wrapped by
+/// the client, and every other session has to log in again.
+#[worker::send]
+pub async fn change_password(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    if !user.check_master_password(&payload.master_password_hash).await? {
+        return Err(AppError::BadRequest("Invalid password".to_string()));
+    }
+
+    let password = crypto::hash_password(&payload.new_master_password_hash).await?;
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    db.batch(vec![
+        query!(
+            &db,
+            "UPDATE users SET master_password_hash = ?1, password_salt = ?2, password_iterations = ?3, key = ?4, master_password_hint = ?5, security_stamp = ?6, updated_at = ?7 WHERE id = ?8",
+            password.hash,
+            password.salt,
+            password.iterations,
+            payload.key,
+            payload.master_password_hint,
+            auth::new_security_stamp(),
+            now,
+            user.id
+        )
+        .map_err(|_| AppError::Database)?,
+        refresh_token::revoke_user(&db, &user.id)?,
+    ])
+    .await?;
+    auth::forget_security_stamp(&user.id);
+
+    Ok(Json(()))
+}
+
+/// Changes the KDF the master key is derived with. That changes the master password hash and
+/// the wrapped user key too, so this works much like a password change.
+#[worker::send]
+pub async fn change_kdf(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<ChangeKdfRequest>,
+) -> Result<Json<()>, AppError> {
+    let kdf = KdfParams {
+        kdf_type: payload.kdf,
+        iterations: payload.kdf_iterations,
+        memory: payload.kdf_memory,
+        parallelism: payload.kdf_parallelism,
+    }
+    .validate()?;
+
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    if !user.check_master_password(&payload.master_password_hash).await? {
+        return Err(AppError::BadRequest("Invalid password".to_string()));
+    }
+
+    let password = crypto::hash_password(&payload.new_master_password_hash).await?;
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    db.batch(vec![
+        query!(
+            &db,
+            "UPDATE users SET kdf_type = ?1, kdf_iterations = ?2, kdf_memory = ?3, kdf_parallelism = ?4, master_password_hash = ?5, password_salt = ?6, password_iterations = ?7, key = ?8, security_stamp = ?9, updated_at = ?10 WHERE id = ?11",
+            kdf.kdf_type,
+            kdf.iterations,
+            kdf.memory,
+            kdf.parallelism,
+            password.hash,
+            password.salt,
+            password.iterations,
+            payload.key,
+            auth::new_security_stamp(),
+            now,
+            user.id
+        )
+        .map_err(|_| AppError::Database)?,
+        refresh_token::revoke_user(&db, &user.id)?,
+    ])
+    .await?;
+    auth::forget_security_stamp(&user.id);
+
+    Ok(Json(()))
+}
+
+#[derive(Deserialize)]
+struct IdRow {
+    id: String,
+}
+
+/// Whether `submitted` names every id in `owned` exactly once, and nothing else.
+fn same_ids<'a>(owned: &[IdRow], submitted: impl ExactSizeIterator<Item = &'a str>) -> bool {
+    let count = submitted.len();
+    let submitted: HashSet<&str> = submitted.collect();
+    let owned: HashSet<&str> = owned.iter().map(|row| row.id.as_str()).collect();
+    count == submitted.len() && submitted == owned
+}
+
+/// Rotates the user key. The client re-encrypts the whole vault with a new key, which is
+/// written in a single batch so a vault is never left half rotated.
+#[worker::send]
+pub async fn rotate_key(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<KeyRotationRequest>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    if !user.check_master_password(&payload.master_password_hash).await? {
+        return Err(AppError::BadRequest("Invalid password".to_string()));
+    }
+
+    // Anything left out would stay encrypted with the old key, which is gone afterwards.
+    let cipher_ids: Vec<IdRow> = query!(&db, "SELECT id FROM ciphers WHERE user_id = ?1", user.id)
+        .map_err(|_| AppError::Database)?
+        .all()
+        .await?
+        .results()?;
+    if !same_ids(&cipher_ids, payload.ciphers.iter().map(|c| c.id.as_str())) {
+        return Err(AppError::BadRequest(
+            "All existing ciphers must be included in the rotation".to_string(),
+        ));
+    }
+    let folder_ids: Vec<IdRow> = query!(&db, "SELECT id FROM folders WHERE user_id = ?1", user.id)
+        .map_err(|_| AppError::Database)?
+        .all()
+        .await?
+        .results()?;
+    if !same_ids(&folder_ids, payload.folders.iter().map(|f| f.id.as_str())) {
+        return Err(AppError::BadRequest(
+            "All existing folders must be included in the rotation".to_string(),
+        ));
+    }
+
+    // Every statement only applies while the user still has as many ciphers and folders as
+    // were checked above, so an item added in between fails the whole rotation instead of
+    // staying behind under the old key.
+    let (cipher_count, folder_count) = (cipher_ids.len() as u32, folder_ids.len() as u32);
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    let mut statements = Vec::with_capacity(payload.ciphers.len() + payload.folders.len() + 2);
+    statements.push(
+        query!(
+            &db,
+            "UPDATE users SET key = ?1, private_key = ?2, security_stamp = ?3, updated_at = ?4 WHERE id = ?5
+             AND (SELECT COUNT(*) FROM ciphers WHERE user_id = ?5) = ?6
+             AND (SELECT COUNT(*) FROM folders WHERE user_id = ?5) = ?7",
+            payload.key,
+            payload.private_key,
+            auth::new_security_stamp(),
+            now,
+            user.id,
+            cipher_count,
+            folder_count
+        )
+        .map_err(|_| AppError::Database)?,
+    );
+    statements.push(refresh_token::revoke_user(&db, &user.id)?);
+    for RotatedCipher { id, cipher } in payload.ciphers {
+        let cipher_data = CipherData {
+            name: cipher.name,
+            notes: cipher.notes,
+            login: cipher.login,
+            card: cipher.card,
+            identity: cipher.identity,
+            secure_note: cipher.secure_note,
+            fields: cipher.fields,
+            password_history: cipher.password_history,
+            reprompt: cipher.reprompt,
+        };
+        let data = serde_json::to_string(&cipher_data).map_err(|_| AppError::Internal)?;
+        statements.push(
+            query!(
+                &db,
+                "UPDATE ciphers SET data = ?1, updated_at = ?2 WHERE id = ?3 AND user_id = ?4
+                 AND (SELECT COUNT(*) FROM ciphers WHERE user_id = ?4) = ?5
+                 AND (SELECT COUNT(*) FROM folders WHERE user_id = ?4) = ?6",
+                data,
+                now,
+                id,
+                user.id,
+                cipher_count,
+                folder_count
+            )
+            .map_err(|_| AppError::Database)?,
+        );
+    }
+    for RotatedFolder { id, name } in payload.folders {
+        statements.push(
+            query!(
+                &db,
+                "UPDATE folders SET name = ?1, updated_at = ?2 WHERE id = ?3 AND user_id = ?4
+                 AND (SELECT COUNT(*) FROM ciphers WHERE user_id = ?4) = ?5
+                 AND (SELECT COUNT(*) FROM folders WHERE user_id = ?4) = ?6",
+                name,
+                now,
+                id,
+                user.id,
+                cipher_count,
+                folder_count
+            )
+            .map_err(|_| AppError::Database)?,
+        );
+    }
+
+    let results = db.batch(statements).await?;
+    let rotated = match results.first() {
+        Some(result) => result.meta()?.and_then(|meta| meta.changes).unwrap_or(0) == 1,
+        None => false,
+    };
+    if !rotated {
+        return Err(AppError::BadRequest(
+            "Ciphers or folders changed during the rotation, try again".to_string(),
+        ));
+    }
+    auth::forget_security_stamp(&user.id);
+
+    Ok(Json(()))
+}
+
+// How long the code for an email change stays valid.
+const EMAIL_CHANGE_TOKEN_TTL_SECONDS: i64 = 60 * 60;
+
+async fn email_in_use(db: &D1Database, email: &str) -> Result<bool, AppError> {
+    let existing: Option<IdRow> = query!(db, "SELECT id FROM users WHERE email = ?1", email)
+        .map_err(|_| AppError::Database)?
+        .first(None)
+        .await
+        .map_err(|_| AppError::Database)?;
+    Ok(existing.is_some())
+}
+
+/// Sends a code to the new address, which `change_email` wants back as proof it is the user's.
+#[worker::send]
+pub async fn send_email_change_token(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<EmailTokenRequest>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    if !user.check_master_password(&payload.master_password_hash).await? {
+        return Err(AppError::BadRequest("Invalid password".to_string()));
+    }
+
+    let new_email = payload.new_email.trim().to_lowercase();
+    if email_in_use(&db, &new_email).await? {
+        return Err(AppError::BadRequest("Email already taken".to_string()));
+    }
+
+    let token = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    let email = Email {
+        to: new_email.clone(),
+        subject: "Your email change".to_string(),
+        body: format!(
+            "To finalize changing your account email to this address, enter the code: {token}\n\n\
+             The code expires in {} minutes. If you did not ask for this, you can ignore this \
+             email.",
+            EMAIL_CHANGE_TOKEN_TTL_SECONDS / 60
+        ),
+    };
+    Mailer::required(&env)?.send(&email).await?;
+
+    query!(
+        &db,
+        "UPDATE users SET email_new = ?1, email_new_token = ?2, email_new_token_sent = ?3 WHERE id = ?4",
+        new_email,
+        token,
+        Utc::now().timestamp(),
+        user.id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok(Json(()))
+}
+
+/// Changes the account email. The email salts the master key, so the client sends the new
+/// master password hash and the user key re-wrapped with the new master key along with it.
+#[worker::send]
+pub async fn change_email(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<ChangeEmailRequest>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    if !user.check_master_password(&payload.master_password_hash).await? {
+        return Err(AppError::BadRequest("Invalid password".to_string()));
+    }
+
+    let new_email = payload.new_email.trim().to_lowercase();
+    let (Some(pending_email), Some(token)) = (&user.email_new, &user.email_new_token) else {
+        return Err(AppError::BadRequest("No email change pending".to_string()));
+    };
+    if *pending_email != new_email {
+        return Err(AppError::BadRequest(
+            "Email does not match the one the code was sent to".to_string(),
+        ));
+    }
+    if Utc::now().timestamp() - user.email_new_token_sent > EMAIL_CHANGE_TOKEN_TTL_SECONDS
+        || !constant_time_eq(token.as_bytes(), payload.token.trim().as_bytes())
+    {
+        return Err(AppError::BadRequest("Token mismatch".to_string()));
+    }
+    // Someone may have registered the address since the code was sent.
+    if email_in_use(&db, &new_email).await? {
+        return Err(AppError::BadRequest("Email already taken".to_string()));
+    }
+
+    let password = crypto::hash_password(&payload.new_master_password_hash).await?;
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    db.batch(vec![
+        query!(
+            &db,
+            "UPDATE users SET email = ?1, email_verified = 1, email_new = NULL, email_new_token = NULL, email_new_token_sent = 0, master_password_hash = ?2, password_salt = ?3, password_iterations = ?4, key = ?5, security_stamp = ?6, updated_at = ?7 WHERE id = ?8",
+            new_email,
+            password.hash,
+            password.salt,
+            password.iterations,
+            payload.key,
+            auth::new_security_stamp(),
+            now,
+            user.id
+        )
+        .map_err(|_| AppError::Database)?,
+        refresh_token::revoke_user(&db, &user.id)?,
+    ])
+    .await?;
+    auth::forget_security_stamp(&user.id);
+
+    Ok(Json(()))
+}
+
+const DELETE_ACCOUNT_ACTION: &str = "delete_account";
+// How long the link in a delete-recover email works.
+const DELETE_RECOVER_TOKEN_HOURS: i64 = 24;
+
+/// Deletes the account and the whole vault.
+#[worker::send]
+pub async fn delete_account(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<PasswordData>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    if !user.check_master_password(&payload.master_password_hash).await? {
+        return Err(AppError::BadRequest("Invalid password".to_string()));
+    }
+
+    db::delete_user(&db, &user.id).await?;
+    auth::forget_security_stamp(&user.id);
+
+    Ok(Json(()))
+}
+
+/// Mails a link to delete the account, for users who forgot their master password. The reply
+/// is the same whether the account exists or not.
+#[worker::send]
+pub async fn delete_recover(
+    State(env): State<Arc<Env>>,
+    headers: HeaderMap,
+    Json(payload): Json<DeleteRecoverRequest>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    RateLimiter::new(&db, SystemClock)
+        .attempt(&LOOKUP_BY_IP, &rate_limit::client_ip(&headers))
+        .await?;
+    let mailer = Mailer::required(&env)?;
+
+    let email = payload.email.trim().to_lowercase();
+    let user: Option<User> = query!(&db, "SELECT * FROM users WHERE email = ?1", email)
+        .map_err(|_| AppError::Database)?
+        .first(None)
+        .await
+        .map_err(|_| AppError::Database)?;
+    let Some(user) = user else {
+        return Ok(Json(()));
+    };
+
+    // Bound to the security stamp, so changing the master password voids the link.
+    let token = auth::encode_bound_action_token(
+        &env,
+        &user.id,
+        DELETE_ACCOUNT_ACTION,
+        &user.security_stamp,
+        Duration::hours(DELETE_RECOVER_TOKEN_HOURS),
+    )?;
+    let link = format!(
+        "{}/#/verify-recover-delete?userId={}&token={}&email={}",
+        config::domain(&env),
+        user.id,
+        token,
+        String::from(js_sys::encode_uri_component(&user.email))
+    );
+    let email = Email {
+        to: user.email,
+        subject: "Delete your account".to_string(),
+        body: format!(
+            "Someone asked to delete the account of this address. Open this link to delete it \
+             along with all of its data:\n\n{link}\n\n\
+             The link expires in {DELETE_RECOVER_TOKEN_HOURS} hours. If you did not ask for \
+             this, you can ignore this email."
+        ),
+    };
+    mailer.send(&email).await?;
+
+    Ok(Json(()))
+}
+
+/// Deletes the account with the link from a delete-recover email.
+#[worker::send]
+pub async fn delete_recover_token(
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<DeleteRecoverTokenRequest>,
+) -> Result<Json<()>, AppError> {
+    let invalid = || AppError::BadRequest("Invalid or expired token".to_string());
+    let (user_id, security_stamp) =
+        auth::decode_bound_action_token(&env, &payload.token, DELETE_ACCOUNT_ACTION)?;
+    if user_id != payload.user_id {
+        return Err(invalid());
+    }
+
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &user_id).await?;
+    if user.security_stamp != security_stamp {
+        return Err(invalid());
+    }
+    db::delete_user(&db, &user.id).await?;
+    auth::forget_security_stamp(&user.id);
+
+    Ok(Json(()))
+}
+
+fn validate_avatar_color(color: Option<&str>) -> Result<(), AppError> {
+    match color {
+        Some(color)
+            if color.len() != 7
+                || !color.starts_with('#')
+                || !color[1..].chars().all(|c| c.is_ascii_hexdigit()) =>
+        {
+            Err(AppError::BadRequest(
+                "Avatar color must be a hex color like #175ddc".to_string(),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[worker::send]
+pub async fn get_profile(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+) -> Result<Json<Profile>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+
+    Ok(Json(sync::build_profile(&db, user).await?))
+}
+
+#[worker::send]
+pub async fn update_profile(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<UpdateProfileRequest>,
+) -> Result<Json<Profile>, AppError> {
+    validate_avatar_color(payload.avatar_color.as_deref())?;
+
+    let db = db::get_db(&env)?;
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    query!(
+        &db,
+        "UPDATE users SET
+            name = CASE WHEN ?1 THEN ?2 ELSE name END,
+            master_password_hint = CASE WHEN ?3 THEN ?4 ELSE master_password_hint END,
+            avatar_color = COALESCE(?5, avatar_color),
+            updated_at = ?6
+         WHERE id = ?7",
+        payload.name.is_some() as i32,
+        payload.name.flatten(),
+        payload.master_password_hint.is_some() as i32,
+        payload.master_password_hint.flatten(),
+        payload.avatar_color,
+        now,
+        claims.sub
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    let user = db::get_user(&db, &claims.sub).await?;
+    Ok(Json(sync::build_profile(&db, user).await?))
+}
+
+#[worker::send]
+pub async fn update_avatar(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<UpdateAvatarRequest>,
+) -> Result<Json<Profile>, AppError> {
+    validate_avatar_color(payload.avatar_color.as_deref())?;
+
+    let db = db::get_db(&env)?;
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    query!(
+        &db,
+        "UPDATE users SET avatar_color = ?1, updated_at = ?2 WHERE id = ?3",
+        payload.avatar_color,
+        now,
+        claims.sub
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    let user = db::get_user(&db, &claims.sub).await?;
+    Ok(Json(sync::build_profile(&db, user).await?))
+}
+
+const API_KEY_LENGTH: usize = 30;
+
+fn new_api_key() -> String {
+    rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(API_KEY_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+async fn save_api_key(db: &D1Database, user_id: &str, api_key: &str) -> Result<String, AppError> {
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    query!(
+        db,
+        "UPDATE users SET api_key = ?1, updated_at = ?2 WHERE id = ?3",
+        api_key,
+        now,
+        user_id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok(now)
+}
+
+/// Returns the client secret for `grant_type=client_credentials`, creating it the first time.
+#[worker::send]
+pub async fn api_key(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<PasswordData>,
+) -> Result<Json<ApiKeyResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    if !user.check_master_password(&payload.master_password_hash).await? {
+        return Err(AppError::BadRequest("Invalid password".to_string()));
+    }
+
+    let (api_key, revision_date) = match user.api_key {
+        Some(api_key) => (api_key, user.updated_at),
+        None => {
+            let api_key = new_api_key();
+            let revision_date = save_api_key(&db, &user.id, &api_key).await?;
+            (api_key, revision_date)
+        }
+    };
+
+    Ok(Json(ApiKeyResponse {
+        api_key,
+        revision_date,
+        object: "apiKey".to_string(),
+    }))
+}
+
+/// Replaces the client secret and revokes the refresh tokens of the sessions opened with the
+/// old one. Their access tokens still work until they expire, within the hour.
+#[worker::send]
+pub async fn rotate_api_key(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<PasswordData>,
+) -> Result<Json<ApiKeyResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    if !user.check_master_password(&payload.master_password_hash).await? {
+        return Err(AppError::BadRequest("Invalid password".to_string()));
+    }
+
+    let api_key = new_api_key();
+    let revision_date = save_api_key(&db, &user.id, &api_key).await?;
+    refresh_token::revoke_api_key(&db, &user.id)?.run().await?;
+
+    Ok(Json(ApiKeyResponse {
+        api_key,
+        revision_date,
+        object: "apiKey".to_string(),
+    }))
+}
@@ -0,0 +1,338 @@
+//! Administration of the deployment, behind the `ADMIN_TOKEN` secret. Without that secret the
+//! admin endpoints are disabled.
+//!
+//! The secret holds either the token itself or its PBKDF2-SHA256 hash, written as
+//! `$pbkdf2-sha256$i=<iterations>$<salt>$<hash>` with unpadded base64. The hash is PBKDF2
+//! rather than Argon2, because WebCrypto derives PBKDF2 natively while Argon2 would take
+//! another dependency compiled into the Worker; Workers cap PBKDF2 at 100000 iterations,
+//! which is therefore the only count accepted. API clients send the token as a bearer token,
+//! the admin panel trades it for a short-lived session cookie.
+
+use axum::{
+    extract::{FromRequestParts, Path, State},
+    http::{header, request::Parts, HeaderMap},
+    Json,
+};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::Duration;
+use constant_time_eq::constant_time_eq;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use worker::{query, send::SendFuture, D1Database, Env};
+
+use crate::{
+    auth, crypto, db,
+    error::AppError,
+    handlers::config,
+    models::{invitation::Invitation, user::bool_from_int},
+    rate_limit::{self, RateLimiter, SystemClock, LOGIN_BY_IP},
+    signup,
+};
+
+const SESSION_COOKIE: &str = "admin_session";
+const SESSION_ACTION: &str = "admin_session";
+const SESSION_MINUTES: i64 = 30;
+// The PBKDF2 iterations a hashed token must use, the most Workers allow.
+const MIN_HASH_ITERATIONS: u32 = 100_000;
+
+/// Proof that the request carries the admin token, as `Authorization: Bearer <ADMIN_TOKEN>` or
+/// through the session cookie of the admin panel.
+pub struct Admin;
+
+impl FromRequestParts<Arc<Env>> for Admin {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<Env>,
+    ) -> Result<Self, Self::Rejection> {
+        admin_token(state)?;
+        if session_cookie(&parts.headers).is_some_and(|session| {
+            auth::decode_action_token(state, session, SESSION_ACTION).is_ok()
+        }) {
+            return Ok(Admin);
+        }
+
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(invalid_token)?;
+        let ip = rate_limit::client_ip(&parts.headers);
+        // D1 and WebCrypto futures aren't `Send`, which axum asks of extractors.
+        SendFuture::new(check_token(state, token, &ip)).await?;
+
+        Ok(Admin)
+    }
+}
+
+fn invalid_token() -> AppError {
+    AppError::Unauthorized("Invalid admin token".to_string())
+}
+
+fn admin_token(env: &Env) -> Result<String, AppError> {
+    env.secret("ADMIN_TOKEN")
+        .map(|secret| secret.to_string())
+        .map_err(|_| AppError::NotFound("Admin is disabled".to_string()))
+}
+
+async fn token_matches(expected: &str, token: &str) -> Result<bool, AppError> {
+    let Some(hashed) = expected.strip_prefix("$pbkdf2-sha256$") else {
+        return Ok(constant_time_eq(token.as_bytes(), expected.as_bytes()));
+    };
+
+    let malformed = || {
+        log::error!("ADMIN_TOKEN is not a valid $pbkdf2-sha256$ hash");
+        AppError::Internal
+    };
+    let decode = |value: &str| {
+        general_purpose::STANDARD_NO_PAD
+            .decode(value.trim_end_matches('='))
+            .map_err(|_| malformed())
+    };
+    let [iterations, salt, hash] = hashed.split('$').collect::<Vec<_>>()[..] else {
+        return Err(malformed());
+    };
+    let iterations: u32 = iterations
+        .strip_prefix("i=")
+        .and_then(|iterations| iterations.parse().ok())
+        .filter(|&iterations| iterations >= MIN_HASH_ITERATIONS)
+        .ok_or_else(malformed)?;
+    let (salt, hash) = (decode(salt)?, decode(hash)?);
+    if salt.is_empty() || hash.is_empty() {
+        return Err(malformed());
+    }
+
+    let derived =
+        crypto::pbkdf2_sha256(token.as_bytes(), &salt, iterations, hash.len() as u32 * 8).await?;
+    Ok(constant_time_eq(&derived, &hash))
+}
+
+/// Checks a token the admin sent, counting wrong ones against the address like failed logins.
+pub async fn check_token(env: &Arc<Env>, token: &str, ip: &str) -> Result<(), AppError> {
+    let expected = admin_token(env)?;
+    let db = db::get_db(env)?;
+    let limiter = RateLimiter::new(&db, SystemClock);
+    limiter.attempt(&LOGIN_BY_IP, ip).await?;
+
+    if !token_matches(&expected, token).await? {
+        return Err(invalid_token());
+    }
+    limiter.refund(&LOGIN_BY_IP, ip).await
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+}
+
+/// The `Set-Cookie` value that starts a panel session.
+pub fn session_cookie_header(env: &Env) -> Result<String, AppError> {
+    let valid_for = Duration::minutes(SESSION_MINUTES);
+    let session = auth::encode_action_token(env, "admin", SESSION_ACTION, valid_for)?;
+    Ok(format!(
+        "{SESSION_COOKIE}={session}; Path=/admin; Max-Age={}; HttpOnly; Secure; SameSite=Strict",
+        valid_for.num_seconds()
+    ))
+}
+
+/// The `Set-Cookie` value that ends a panel session.
+pub fn expired_session_cookie_header() -> String {
+    format!("{SESSION_COOKIE}=; Path=/admin; Max-Age=0; HttpOnly; Secure; SameSite=Strict")
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InviteRequest {
+    email: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvitationResponse {
+    id: String,
+    email: String,
+    expires_at: i64,
+    used_at: Option<String>,
+    created_at: String,
+    // Only when the invitation is issued: the token and the signup link that carries it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link: Option<String>,
+}
+
+impl From<Invitation> for InvitationResponse {
+    fn from(invitation: Invitation) -> Self {
+        InvitationResponse {
+            id: invitation.id,
+            email: invitation.email,
+            expires_at: invitation.expires_at,
+            used_at: invitation.used_at,
+            created_at: invitation.created_at,
+            token: None,
+            link: None,
+        }
+    }
+}
+
+/// A user as the admin sees it: the account, not the vault.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all(serialize = "camelCase"))]
+pub struct AdminUser {
+    pub id: String,
+    pub email: String,
+    pub name: Option<String>,
+    #[serde(deserialize_with = "bool_from_int::deserialize")]
+    pub email_verified: bool,
+    #[serde(deserialize_with = "bool_from_int::deserialize")]
+    pub enabled: bool,
+    pub cipher_count: i64,
+    pub folder_count: i64,
+    // The last login or token refresh of any of the user's devices
+    pub last_active_at: Option<String>,
+    pub created_at: String,
+}
+
+pub async fn list_users(db: &D1Database) -> Result<Vec<AdminUser>, AppError> {
+    Ok(query!(
+        db,
+        "SELECT users.id, users.email, users.name, users.email_verified, users.enabled, users.created_at,
+            (SELECT COUNT(*) FROM ciphers WHERE ciphers.user_id = users.id) AS cipher_count,
+            (SELECT COUNT(*) FROM folders WHERE folders.user_id = users.id) AS folder_count,
+            (SELECT MAX(last_active_at) FROM devices WHERE devices.user_id = users.id) AS last_active_at
+         FROM users ORDER BY users.created_at"
+    )
+    .all()
+    .await?
+    .results()?)
+}
+
+#[worker::send]
+pub async fn get_users(
+    _admin: Admin,
+    State(env): State<Arc<Env>>,
+) -> Result<Json<Vec<AdminUser>>, AppError> {
+    let db = db::get_db(&env)?;
+    Ok(Json(list_users(&db).await?))
+}
+
+/// Suspends the account and ends its sessions. The vault is kept.
+#[worker::send]
+pub async fn disable_user(
+    _admin: Admin,
+    State(env): State<Arc<Env>>,
+    Path(id): Path<String>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &id).await?;
+    query!(&db, "UPDATE users SET enabled = 0 WHERE id = ?1", user.id)
+        .map_err(|_| AppError::Database)?
+        .run()
+        .await?;
+    auth::rotate_security_stamp(&db, &user.id).await?;
+
+    Ok(Json(()))
+}
+
+#[worker::send]
+pub async fn enable_user(
+    _admin: Admin,
+    State(env): State<Arc<Env>>,
+    Path(id): Path<String>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &id).await?;
+    query!(&db, "UPDATE users SET enabled = 1 WHERE id = ?1", user.id)
+        .map_err(|_| AppError::Database)?
+        .run()
+        .await?;
+    auth::forget_security_stamp(&user.id);
+
+    Ok(Json(()))
+}
+
+/// Logs the user out of every device.
+#[worker::send]
+pub async fn deauthorize_user(
+    _admin: Admin,
+    State(env): State<Arc<Env>>,
+    Path(id): Path<String>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &id).await?;
+    auth::rotate_security_stamp(&db, &user.id).await?;
+
+    Ok(Json(()))
+}
+
+/// Deletes the account and the whole vault.
+#[worker::send]
+pub async fn delete_user(
+    _admin: Admin,
+    State(env): State<Arc<Env>>,
+    Path(id): Path<String>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &id).await?;
+    db::delete_user(&db, &user.id).await?;
+    auth::forget_security_stamp(&user.id);
+
+    Ok(Json(()))
+}
+
+#[worker::send]
+pub async fn create_invitation(
+    _admin: Admin,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<InviteRequest>,
+) -> Result<Json<InvitationResponse>, AppError> {
+    let email = payload.email.trim();
+    if !email.contains('@') {
+        return Err(AppError::BadRequest("Invalid email address".to_string()));
+    }
+
+    let db = db::get_db(&env)?;
+    let (invitation, token) = signup::invite(&env, &db, email).await?;
+    // The web vault's finish-signup page sends the token back as `emailVerificationToken`.
+    let query = serde_urlencoded::to_string([("token", &token), ("email", &invitation.email)])
+        .map_err(|_| AppError::Internal)?;
+    let link = format!("{}/#/finish-signup?{query}", config::domain(&env));
+
+    Ok(Json(InvitationResponse {
+        token: Some(token),
+        link: Some(link),
+        ..invitation.into()
+    }))
+}
+
+#[worker::send]
+pub async fn get_invitations(
+    _admin: Admin,
+    State(env): State<Arc<Env>>,
+) -> Result<Json<Vec<InvitationResponse>>, AppError> {
+    let db = db::get_db(&env)?;
+    let invitations = signup::list(&db).await?;
+
+    Ok(Json(invitations.into_iter().map(Into::into).collect()))
+}
+
+#[worker::send]
+pub async fn revoke_invitation(
+    _admin: Admin,
+    State(env): State<Arc<Env>>,
+    Path(id): Path<String>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    if !signup::revoke(&db, &id).await? {
+        return Err(AppError::NotFound("Invitation not found".to_string()));
+    }
+
+    Ok(Json(()))
+}
@@ -0,0 +1,242 @@
+//! The admin panel: a few server-rendered pages on top of the JSON endpoints of
+//! [`admin`](super::admin), which its buttons call with the session cookie.
+
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    Form,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use worker::Env;
+
+use crate::{
+    db,
+    error::AppError,
+    handlers::admin::{self, Admin, AdminUser},
+    models::invitation::Invitation,
+    rate_limit, signup,
+};
+
+const STYLE: &str = "
+body { font-family: system-ui, sans-serif; margin: 2rem; color: #1b2029; }
+table { border-collapse: collapse; width: 100%; margin-bottom: 2rem; }
+th, td { text-align: left; padding: .4rem .6rem; border-bottom: 1px solid #dde1e7; }
+button { margin-right: .3rem; }
+.disabled { color: #8a919d; }
+.error { color: #c83522; }
+";
+
+const SCRIPT: &str = "
+async function call(method, path, body) {
+  const response = await fetch(path, {
+    method,
+    headers: body ? { 'Content-Type': 'application/json' } : {},
+    body: body ? JSON.stringify(body) : undefined,
+  });
+  const result = await response.json().catch(() => null);
+  if (!response.ok) {
+    alert((result && result.error) || response.statusText);
+    return null;
+  }
+  return result;
+}
+document.addEventListener('click', async (event) => {
+  const button = event.target.closest('button[data-path]');
+  if (!button || (button.dataset.confirm && !confirm(button.dataset.confirm))) return;
+  if (await call(button.dataset.method, button.dataset.path) !== null) location.reload();
+});
+document.getElementById('invite').addEventListener('submit', async (event) => {
+  event.preventDefault();
+  const invitation = await call('POST', '/admin/invitations', { email: event.target.email.value });
+  if (invitation) document.getElementById('invite-link').textContent = invitation.link;
+});
+";
+
+#[derive(Debug, Deserialize)]
+pub struct LoginForm {
+    token: String,
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn page(title: &str, body: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html>\n<html lang=\"en\"><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>{title}</title><style>{STYLE}</style></head><body>{body}</body></html>"
+    ))
+}
+
+fn login_page(error: Option<&str>) -> Html<String> {
+    let error = error
+        .map(|error| format!("<p class=\"error\">{}</p>", escape(error)))
+        .unwrap_or_default();
+    page(
+        "Admin",
+        &format!(
+            "<h1>Admin</h1>{error}\
+             <form method=\"post\" action=\"/admin/login\">\
+             <input type=\"password\" name=\"token\" placeholder=\"Admin token\" autofocus required> \
+             <button type=\"submit\">Log in</button></form>"
+        ),
+    )
+}
+
+/// A button that calls an admin endpoint, then reloads the page.
+fn action(method: &str, path: &str, label: &str, confirm: Option<&str>) -> String {
+    let confirm = confirm
+        .map(|confirm| format!(" data-confirm=\"{}\"", escape(confirm)))
+        .unwrap_or_default();
+    format!(
+        "<button data-method=\"{method}\" data-path=\"{}\"{confirm}>{label}</button>",
+        escape(path)
+    )
+}
+
+fn user_row(user: &AdminUser) -> String {
+    let path = |action: &str| format!("/admin/users/{}/{action}", user.id);
+    let toggle = if user.enabled {
+        action(
+            "POST",
+            &path("disable"),
+            "Disable",
+            Some(&format!("Disable {} and log them out?", user.email)),
+        )
+    } else {
+        action("POST", &path("enable"), "Enable", None)
+    };
+    let actions = [
+        toggle,
+        action(
+            "POST",
+            &path("deauthorize"),
+            "Log out",
+            Some(&format!("Log {} out of every device?", user.email)),
+        ),
+        action(
+            "DELETE",
+            &format!("/admin/users/{}", user.id),
+            "Delete",
+            Some(&format!(
+                "Delete {} and their whole vault? This can't be undone.",
+                user.email
+            )),
+        ),
+    ]
+    .concat();
+
+    format!(
+        "<tr{}><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{actions}</td></tr>",
+        if user.enabled { "" } else { " class=\"disabled\"" },
+        escape(&user.email),
+        escape(user.name.as_deref().unwrap_or("")),
+        if user.email_verified { "yes" } else { "no" },
+        user.cipher_count,
+        user.folder_count,
+        escape(user.last_active_at.as_deref().unwrap_or("never")),
+        escape(&user.created_at),
+    )
+}
+
+fn invitation_row(invitation: &Invitation) -> String {
+    let status = match &invitation.used_at {
+        Some(used_at) => format!("used {}", escape(used_at)),
+        None if invitation.expires_at < chrono::Utc::now().timestamp() => "expired".to_string(),
+        None => "pending".to_string(),
+    };
+    format!(
+        "<tr><td>{}</td><td>{status}</td><td>{}</td><td>{}</td></tr>",
+        escape(&invitation.email),
+        escape(&invitation.created_at),
+        action(
+            "DELETE",
+            &format!("/admin/invitations/{}", invitation.id),
+            "Revoke",
+            None
+        ),
+    )
+}
+
+/// The users and invitations, or the login form without a session.
+#[worker::send]
+pub async fn index(
+    admin: Result<Admin, AppError>,
+    State(env): State<Arc<Env>>,
+) -> Result<Response, AppError> {
+    match admin {
+        Ok(Admin) => {}
+        Err(AppError::Unauthorized(_)) => return Ok(login_page(None).into_response()),
+        Err(error) => return Err(error),
+    }
+
+    let db = db::get_db(&env)?;
+    let users = admin::list_users(&db).await?;
+    let invitations = signup::list(&db).await?;
+
+    let body = format!(
+        "<form method=\"post\" action=\"/admin/logout\" style=\"float: right\">\
+         <button type=\"submit\">Log out</button></form>\
+         <h1>Users</h1><table><tr><th>Email</th><th>Name</th><th>Verified</th><th>Items</th>\
+         <th>Folders</th><th>Last active</th><th>Created</th><th></th></tr>{}</table>\
+         <h1>Invitations</h1>\
+         <form id=\"invite\"><input type=\"email\" name=\"email\" placeholder=\"Email\" required> \
+         <button type=\"submit\">Invite</button></form><p><output id=\"invite-link\"></output></p>\
+         <table><tr><th>Email</th><th>Status</th><th>Created</th><th></th></tr>{}</table>\
+         <script>{SCRIPT}</script>",
+        users.iter().map(user_row).collect::<String>(),
+        invitations.iter().map(invitation_row).collect::<String>(),
+    );
+
+    Ok(page("Admin", &body).into_response())
+}
+
+/// Trades the admin token for a session cookie.
+#[worker::send]
+pub async fn login(
+    State(env): State<Arc<Env>>,
+    headers: HeaderMap,
+    Form(form): Form<LoginForm>,
+) -> Result<Response, AppError> {
+    let ip = rate_limit::client_ip(&headers);
+    match admin::check_token(&env, &form.token, &ip).await {
+        Ok(()) => {}
+        Err(AppError::Unauthorized(message)) => {
+            return Ok((StatusCode::UNAUTHORIZED, login_page(Some(&message))).into_response())
+        }
+        Err(AppError::TooManyRequests(_)) => {
+            let message = "Too many attempts, try again later.";
+            return Ok((StatusCode::TOO_MANY_REQUESTS, login_page(Some(message))).into_response());
+        }
+        Err(error) => return Err(error),
+    }
+
+    Ok((
+        [(header::SET_COOKIE, admin::session_cookie_header(&env)?)],
+        Redirect::to("/admin"),
+    )
+        .into_response())
+}
+
+#[worker::send]
+pub async fn logout() -> Response {
+    (
+        [(header::SET_COOKIE, admin::expired_session_cookie_header())],
+        Redirect::to("/admin"),
+    )
+        .into_response()
+}
@@ -0,0 +1,315 @@
+//! Login with device.
+//!
+//! A device that wants to log in without the master password creates an auth request with a
+//! fresh public key and a random access code, and shows the user a fingerprint of the key. One
+//! of the user's signed in clients approves it by sending the user key encrypted to that public
+//! key. The new device polls for the answer with its access code, decrypts the user key and
+//! logs in through the `password` grant with the access code in place of the password.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    Json,
+};
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{Duration, Utc};
+use constant_time_eq::constant_time_eq;
+use ring::digest;
+use std::sync::Arc;
+use uuid::Uuid;
+use worker::{query, D1Database, Env};
+
+use crate::{
+    auth::Claims,
+    db,
+    error::AppError,
+    handlers::config,
+    models::{
+        auth_request::{
+            AuthRequest, AuthRequestCode, AuthRequestListResponse, AuthRequestResponse,
+            CreateAuthRequest, UpdateAuthRequest, AUTH_REQUEST_LOGIN, AUTH_REQUEST_UNLOCK,
+        },
+        device::DeviceType,
+        user::User,
+    },
+    rate_limit::{self, RateLimiter, SystemClock, LOOKUP_BY_IP},
+};
+
+const AUTH_REQUEST_TTL_MINUTES: i64 = 15;
+
+fn hash_access_code(code: &str) -> String {
+    general_purpose::STANDARD.encode(digest::digest(&digest::SHA256, code.as_bytes()))
+}
+
+fn check_access_code(request: &AuthRequest, code: &str) -> bool {
+    constant_time_eq(
+        request.access_code_hash.as_bytes(),
+        hash_access_code(code).as_bytes(),
+    )
+}
+
+fn is_expired(request: &AuthRequest) -> bool {
+    request.expires_at < Utc::now().timestamp()
+}
+
+async fn find_request(db: &D1Database, id: &str) -> Result<Option<AuthRequest>, AppError> {
+    query!(db, "SELECT * FROM auth_requests WHERE id = ?1", id)
+        .map_err(|_| AppError::Database)?
+        .first(None)
+        .await
+        .map_err(|_| AppError::Database)
+}
+
+/// Spends an approved request on a `password` grant, where the access code is sent as the
+/// password. Returns whether it was valid; a request logs in once, from the device that made it.
+pub async fn redeem(
+    db: &D1Database,
+    id: &str,
+    user: &User,
+    access_code: &str,
+    device_identifier: Option<&str>,
+) -> Result<bool, AppError> {
+    let Some(request) = find_request(db, id).await? else {
+        return Ok(false);
+    };
+    if request.user_id != user.id
+        || request.r#type != AUTH_REQUEST_LOGIN
+        || request.approved != Some(1)
+        || request.authentication_date.is_some()
+        || is_expired(&request)
+        || device_identifier != Some(request.request_device_identifier.as_str())
+        || !check_access_code(&request, access_code)
+    {
+        return Ok(false);
+    }
+
+    // Conditional, so two logins racing with the same approval can't both succeed.
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    let result = query!(
+        db,
+        "UPDATE auth_requests SET authentication_date = ?1 WHERE id = ?2 AND authentication_date IS NULL",
+        now,
+        request.id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok(result.meta()?.and_then(|meta| meta.changes).unwrap_or(0) == 1)
+}
+
+/// Asks the user's other devices to approve a login. Anonymous, the requesting device isn't
+/// logged in yet. An earlier pending request from the same device is replaced.
+#[worker::send]
+pub async fn create_auth_request(
+    State(env): State<Arc<Env>>,
+    headers: HeaderMap,
+    Json(payload): Json<CreateAuthRequest>,
+) -> Result<Json<AuthRequestResponse>, AppError> {
+    if payload.r#type != AUTH_REQUEST_LOGIN && payload.r#type != AUTH_REQUEST_UNLOCK {
+        return Err(AppError::BadRequest(
+            "Unsupported auth request type".to_string(),
+        ));
+    }
+    if payload.access_code.len() < 20 {
+        return Err(AppError::BadRequest("Access code is too short".to_string()));
+    }
+
+    let db = db::get_db(&env)?;
+    let ip = rate_limit::client_ip(&headers);
+    RateLimiter::new(&db, SystemClock)
+        .attempt(&LOOKUP_BY_IP, &ip)
+        .await?;
+
+    let user: Option<User> = query!(
+        &db,
+        "SELECT * FROM users WHERE email = ?1",
+        payload.email.to_lowercase()
+    )
+    .map_err(|_| AppError::Database)?
+    .first(None)
+    .await
+    .map_err(|_| AppError::Database)?;
+
+    let device_type = headers
+        .get("Device-Type")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DeviceType::UnknownBrowser as i32);
+    let now = Utc::now();
+    let request = AuthRequest {
+        id: Uuid::new_v4().to_string(),
+        // Unknown emails get an answer that looks the same, so this can't tell which accounts
+        // exist. Nothing is stored for them and nobody is asked.
+        user_id: user
+            .as_ref()
+            .map_or_else(|| Uuid::new_v4().to_string(), |user| user.id.clone()),
+        r#type: payload.r#type,
+        request_device_identifier: payload.device_identifier,
+        request_device_type: device_type,
+        request_ip: ip,
+        public_key: payload.public_key,
+        access_code_hash: hash_access_code(&payload.access_code),
+        approved: None,
+        response_device_identifier: None,
+        key: None,
+        master_password_hash: None,
+        response_date: None,
+        authentication_date: None,
+        expires_at: (now + Duration::minutes(AUTH_REQUEST_TTL_MINUTES)).timestamp(),
+        created_at: now.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+    };
+    if user.is_none() {
+        return Ok(Json(AuthRequestResponse::new(
+            request,
+            config::domain(&env),
+        )));
+    }
+
+    db.batch(vec![
+        query!(
+            &db,
+            "DELETE FROM auth_requests WHERE user_id = ?1 AND (expires_at < ?2 OR (request_device_identifier = ?3 AND approved IS NULL))",
+            request.user_id,
+            now.timestamp(),
+            request.request_device_identifier
+        )
+        .map_err(|_| AppError::Database)?,
+        query!(
+            &db,
+            "INSERT INTO auth_requests (id, user_id, type, request_device_identifier, request_device_type, request_ip, public_key, access_code_hash, expires_at, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            request.id,
+            request.user_id,
+            request.r#type,
+            request.request_device_identifier,
+            request.request_device_type,
+            request.request_ip,
+            request.public_key,
+            request.access_code_hash,
+            request.expires_at,
+            request.created_at
+        )
+        .map_err(|_| AppError::Database)?,
+    ])
+    .await?;
+
+    Ok(Json(AuthRequestResponse::new(
+        request,
+        config::domain(&env),
+    )))
+}
+
+/// The user's pending requests, for the signed in clients to approve or deny.
+#[worker::send]
+pub async fn get_auth_requests(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+) -> Result<Json<AuthRequestListResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let requests: Vec<AuthRequest> = query!(
+        &db,
+        "SELECT * FROM auth_requests WHERE user_id = ?1 AND approved IS NULL AND expires_at >= ?2 ORDER BY created_at DESC",
+        claims.sub,
+        Utc::now().timestamp()
+    )
+    .map_err(|_| AppError::Database)?
+    .all()
+    .await?
+    .results()?;
+
+    let origin = config::domain(&env);
+    Ok(Json(AuthRequestListResponse {
+        data: requests
+            .into_iter()
+            .map(|request| AuthRequestResponse::new(request, origin.clone()))
+            .collect(),
+        object: "list".to_string(),
+        continuation_token: None,
+    }))
+}
+
+/// Approves or denies a request. A request is answered once.
+#[worker::send]
+pub async fn update_auth_request(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Path(id): Path<String>,
+    Json(payload): Json<UpdateAuthRequest>,
+) -> Result<Json<AuthRequestResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let request = find_request(&db, &id)
+        .await?
+        .filter(|request| request.user_id == claims.sub && !is_expired(request))
+        .ok_or_else(|| AppError::NotFound("Auth request not found".to_string()))?;
+    if request.approved.is_some() {
+        return Err(AppError::BadRequest(
+            "Auth request has already been answered".to_string(),
+        ));
+    }
+
+    let (key, master_password_hash) = if payload.request_approved {
+        let key = payload
+            .key
+            .ok_or_else(|| AppError::BadRequest("Missing key".to_string()))?;
+        (Some(key), payload.master_password_hash)
+    } else {
+        (None, None)
+    };
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    let request = AuthRequest {
+        approved: Some(payload.request_approved as i32),
+        response_device_identifier: Some(payload.device_identifier),
+        key,
+        master_password_hash,
+        response_date: Some(now),
+        ..request
+    };
+
+    let result = query!(
+        &db,
+        "UPDATE auth_requests SET approved = ?1, response_device_identifier = ?2, key = ?3, master_password_hash = ?4, response_date = ?5 WHERE id = ?6 AND approved IS NULL",
+        request.approved,
+        request.response_device_identifier,
+        request.key,
+        request.master_password_hash,
+        request.response_date,
+        request.id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+    if result.meta()?.and_then(|meta| meta.changes).unwrap_or(0) != 1 {
+        return Err(AppError::BadRequest(
+            "Auth request has already been answered".to_string(),
+        ));
+    }
+
+    Ok(Json(AuthRequestResponse::new(
+        request,
+        config::domain(&env),
+    )))
+}
+
+/// The answer to a request, polled by the device that made it with its access code.
+#[worker::send]
+pub async fn get_auth_request_response(
+    State(env): State<Arc<Env>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Query(params): Query<AuthRequestCode>,
+) -> Result<Json<AuthRequestResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    RateLimiter::new(&db, SystemClock)
+        .attempt(&LOOKUP_BY_IP, &rate_limit::client_ip(&headers))
+        .await?;
+
+    let request = find_request(&db, &id)
+        .await?
+        .filter(|request| !is_expired(request) && check_access_code(request, &params.code))
+        .ok_or_else(|| AppError::NotFound("Auth request not found".to_string()))?;
+
+    Ok(Json(AuthRequestResponse::new(
+        request,
+        config::domain(&env),
+    )))
+}
@@ -1,8 +1,30 @@
-use axum::Json;
+use axum::{extract::State, Json};
 use serde_json::{json, Value};
+use std::sync::Arc;
+use worker::Env;
+
+use crate::{db, error::AppError, oidc, signup};
+
+const DEFAULT_DOMAIN: &str = "https://warden-worker.deepgauravraj.workers.dev";
+
+/// The public URL of this deployment, taken from the `DOMAIN` variable.
+pub fn domain(env: &Env) -> String {
+    env.var("DOMAIN")
+        .map(|domain| domain.to_string())
+        .unwrap_or_else(|_| DEFAULT_DOMAIN.to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// The WebAuthn relying party id, which is the host part of the domain.
+pub fn rp_id(env: &Env) -> String {
+    let domain = domain(env);
+    let host = domain.split_once("://").map_or(domain.as_str(), |(_, rest)| rest);
+    host.split(['/', ':']).next().unwrap_or(host).to_string()
+}
 
 #[worker::send]
-pub async fn config() -> Json<Value> {
+pub async fn config(State(env): State<Arc<Env>>) -> Result<Json<Value>, AppError> {
     // let domain = crate::CONFIG.domain();
     // Official available feature flags can be found here:
     // Server (v2025.6.2): https://github.com/bitwarden/server/blob/d094be3267f2030bd0dc62106bc6871cf82682f5/src/Core/Constants.cs#L103
@@ -17,8 +39,9 @@ pub async fn config() -> Json<Value> {
     // feature_states.insert("enable-pm-flight-recorder".to_string(), true);
     // feature_states.insert("mobile-error-reporting".to_string(), true);
 
-    let domain = "https://warden-worker.deepgauravraj.workers.dev";
-    Json(json!({
+    let domain = domain(&env);
+    let registration_open = signup::is_open(&env, &db::get_db(&env)?).await?;
+    Ok(Json(json!({
         // Note: The clients use this version to handle backwards compatibility concerns
         // This means they expect a version that closely matches the Bitwarden server version
         // We should make sure that we keep this updated when we support the new server features
@@ -31,14 +54,15 @@ pub async fn config() -> Json<Value> {
           "url": "https://github.com/dani-garcia/vaultwarden"
         },
         "settings": {
-            "disableUserRegistration": true,
+            "disableUserRegistration": !registration_open,
         },
         "environment": {
           "vault": domain,
           "api": format!("{domain}/api"),
           "identity": format!("{domain}/identity"),
           "notifications": format!("{domain}/notifications"),
-          "sso": format!("{domain}/sso"),
+          // Empty tells the clients there is no SSO.
+          "sso": if oidc::config(&env).is_some() { format!("{domain}/sso") } else { String::new() },
           "cloudRegion": null,
         },
         // Bitwarden uses this for the self-hosted servers to indicate the default push technology
@@ -51,5 +75,5 @@ pub async fn config() -> Json<Value> {
             // "flexible-collections-v-1": false
         },
         "object": "config",
-    }))
+    })))
 }
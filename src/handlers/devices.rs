@@ -1,14 +1,21 @@
-use axum::{extract::State, Json};
-
-use axum::http::HeaderMap;
+use axum::{
+    extract::{Path, State},
+    http::HeaderMap,
+    Json,
+};
 use chrono::Utc;
+use serde::Deserialize;
 use std::sync::Arc;
 use worker::{query, Env};
-use axum::extract::Path;
 
-use crate::db;
-use crate::error::AppError;
-use serde::Deserialize;
+use crate::{
+    auth::{self, Claims},
+    db,
+    error::AppError,
+    models::device::{Device, DeviceListResponse, DeviceResponse, UpdateDeviceRequest},
+    rate_limit::{self, RateLimiter, SystemClock, LOOKUP_BY_IP},
+    refresh_token,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct PushTokenRequest {
@@ -46,6 +53,9 @@ pub async fn get_known_device(
 
     if let (Some(email), Some(device_identifier)) = (email, device_identifier) {
          let db = db::get_db(&env)?;
+         RateLimiter::new(&db, SystemClock)
+             .attempt(&LOOKUP_BY_IP, &rate_limit::client_ip(&headers))
+             .await?;
          // Check if device exists for this user (by email lookup)
          // First get user id from email
  
@@ -102,3 +112,141 @@ pub async fn put_token(
 
     Ok(Json(()))
 }
+
+async fn find_user_device(
+    db: &worker::D1Database,
+    user_id: &str,
+    column: &str,
+    value: &str,
+) -> Result<Device, AppError> {
+    // `column` is one of our own, never input.
+    let device: Option<Device> = db
+        .prepare(format!("SELECT * FROM devices WHERE {column} = ?1 AND user_id = ?2"))
+        .bind(&[value.into(), user_id.into()])?
+        .first(None)
+        .await
+        .map_err(|_| AppError::Database)?;
+
+    device.ok_or_else(|| AppError::NotFound("Device not found".to_string()))
+}
+
+#[worker::send]
+pub async fn get_devices(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+) -> Result<Json<DeviceListResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let devices: Vec<Device> = query!(
+        &db,
+        "SELECT * FROM devices WHERE user_id = ?1 ORDER BY last_active_at DESC",
+        claims.sub
+    )
+    .map_err(|_| AppError::Database)?
+    .all()
+    .await?
+    .results()?;
+
+    Ok(Json(DeviceListResponse {
+        data: devices.into_iter().map(DeviceResponse::from).collect(),
+        object: "list".to_string(),
+        continuation_token: None,
+    }))
+}
+
+#[worker::send]
+pub async fn get_device_by_identifier(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Path(identifier): Path<String>,
+) -> Result<Json<DeviceResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let device = find_user_device(&db, &claims.sub, "identifier", &identifier).await?;
+
+    Ok(Json(device.into()))
+}
+
+#[worker::send]
+pub async fn update_device(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Path(id): Path<String>,
+    Json(payload): Json<UpdateDeviceRequest>,
+) -> Result<Json<DeviceResponse>, AppError> {
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return Err(AppError::BadRequest("Device name is required".to_string()));
+    }
+
+    let db = db::get_db(&env)?;
+    let device = find_user_device(&db, &claims.sub, "id", &id).await?;
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    query!(
+        &db,
+        "UPDATE devices SET name = ?1, updated_at = ?2 WHERE id = ?3",
+        name,
+        now,
+        device.id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok(Json(
+        Device {
+            name: Some(name.to_string()),
+            updated_at: now,
+            ..device
+        }
+        .into(),
+    ))
+}
+
+/// Removes a device from the account, logging it out and forgetting its remembered two-step
+/// login. Its access token stops working too, and logging in again brings the device back.
+#[worker::send]
+pub async fn deactivate_device(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Path(id): Path<String>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let device = find_user_device(&db, &claims.sub, "id", &id).await?;
+
+    db.batch(vec![
+        refresh_token::revoke_device(&db, &device.id)?,
+        query!(&db, "DELETE FROM devices WHERE id = ?1", device.id)
+            .map_err(|_| AppError::Database)?,
+    ])
+    .await?;
+    auth::forget_security_stamp(&claims.sub);
+
+    Ok(Json(()))
+}
+
+/// Logs a single device out by revoking its refresh tokens. Its current access token stays
+/// valid until it expires.
+#[worker::send]
+pub async fn revoke_device(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Path(id): Path<String>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let device = find_user_device(&db, &claims.sub, "id", &id).await?;
+
+    refresh_token::revoke_device(&db, &device.id)?.run().await?;
+
+    Ok(Json(()))
+}
+
+/// Logs every device of the user out.
+#[worker::send]
+pub async fn revoke_all(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    refresh_token::revoke_user(&db, &claims.sub)?.run().await?;
+
+    Ok(Json(()))
+}
@@ -1,14 +1,24 @@
-use axum::{extract::State, Form, Json};
+use axum::{extract::State, http::HeaderMap, Form, Json};
 use chrono::{Duration, Utc};
 use constant_time_eq::constant_time_eq;
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::sync::Arc;
-use worker::{query, Env, console_log};
+use worker::{query, D1Database, Env};
 use uuid::Uuid;
 
-use crate::{auth::Claims, db, error::AppError, models::user::User, models::device::Device};
+use crate::{
+    auth::{self, Claims, SCOPE_API, SCOPE_OFFLINE_ACCESS},
+    crypto, db,
+    error::AppError,
+    handlers::{auth_requests, sso, two_factor},
+    jwt,
+    models::device::Device,
+    models::two_factor::TwoFactorType,
+    models::user::User,
+    rate_limit::{self, RateLimiter, SystemClock, LOGIN_BY_IP},
+    refresh_token::{self, Session},
+};
 
 #[derive(Debug, Deserialize)]
 pub struct TokenRequest {
@@ -16,10 +26,28 @@ pub struct TokenRequest {
     username: Option<String>,
     password: Option<String>, // This is the masterPasswordHash
     refresh_token: Option<String>,
+    scope: Option<String>,
+    client_id: Option<String>,     // user.<uuid> for the API key
+    client_secret: Option<String>, // The API key
+    #[serde(alias = "deviceIdentifier")]
     device_identifier: Option<String>,
+    #[serde(alias = "deviceName")]
     device_name: Option<String>,
+    #[serde(alias = "deviceType")]
     device_type: Option<i32>,
+    #[serde(alias = "devicePushToken")]
     device_push_token: Option<String>,
+    #[serde(rename = "twoFactorToken")]
+    two_factor_token: Option<String>,
+    #[serde(rename = "twoFactorProvider")]
+    two_factor_provider: Option<i32>,
+    #[serde(rename = "twoFactorRemember")]
+    two_factor_remember: Option<i32>,
+    code: Option<String>, // SSO authorization code
+    code_verifier: Option<String>,
+    redirect_uri: Option<String>,
+    #[serde(rename = "authRequest")]
+    auth_request: Option<String>, // Login with device, `password` holds the access code then
 }
 
 #[derive(Debug, Serialize)]
@@ -45,6 +73,8 @@ pub struct TokenResponse {
     force_password_reset: bool,
     #[serde(rename = "UserDecryptionOptions")]
     user_decryption_options: UserDecryptionOptions,
+    #[serde(rename = "TwoFactorToken", skip_serializing_if = "Option::is_none")]
+    two_factor_token: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -75,8 +105,21 @@ pub struct Kdf {
     pub parallelism: Option<i32>,
 }
 
-fn generate_tokens_and_response(
+/// Turns a disabled account away. Each grant calls it as soon as it knows who is logging in,
+/// before anything is sent, saved or spent on the user's behalf.
+fn check_enabled(user: &User) -> Result<(), AppError> {
+    if user.enabled {
+        Ok(())
+    } else {
+        Err(auth::account_disabled())
+    }
+}
+
+async fn generate_tokens_and_response(
+    db: &D1Database,
     user: User,
+    device: &Device,
+    session: Session,
     env: &Arc<Env>,
 ) -> Result<Json<TokenResponse>, AppError> {
     let now = Utc::now();
@@ -90,35 +133,28 @@ fn generate_tokens_and_response(
         premium: true,
         name: user.name.clone().unwrap_or_else(|| "User".to_string()),
         email: user.email.clone(),
-        email_verified: true,
+        email_verified: user.email_verified,
         amr: vec!["Application".into()],
+        sstamp: user.security_stamp.clone(),
+        scope: session.scope.clone(),
+        client_id: session.client_id.clone(),
+        device: device.identifier.clone(),
+        token_type: auth::ACCESS_TOKEN.to_string(),
     };
 
-    let jwt_secret = env.secret("JWT_SECRET")?.to_string();
-    let access_token = encode(
-        &Header::default(),
-        &access_claims,
-        &EncodingKey::from_secret(jwt_secret.as_ref()),
-    )?;
+    let access_token = jwt::sign(env, &access_claims)?;
 
-    let refresh_expires_in = Duration::days(30);
-    let refresh_exp = (now + refresh_expires_in).timestamp() as usize;
-    let refresh_claims = Claims {
-        sub: user.id.clone(),
-        exp: refresh_exp,
-        nbf: now.timestamp() as usize,
-        premium: true,
-        name: user.name.unwrap_or_else(|| "User".to_string()),
-        email: user.email.clone(),
-        email_verified: true,
-        amr: vec!["Application".into()],
-    };
-    let jwt_refresh_secret = env.secret("JWT_REFRESH_SECRET")?.to_string();
-    let refresh_token = encode(
-        &Header::default(),
-        &refresh_claims,
-        &EncodingKey::from_secret(jwt_refresh_secret.as_ref()),
-    )?;
+    query!(
+        db,
+        "UPDATE devices SET last_active_at = ?1 WHERE id = ?2",
+        now.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        device.id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    let refresh_token = refresh_token::issue(db, &session).await?;
 
     Ok(Json(TokenResponse {
         access_token,
@@ -138,18 +174,189 @@ fn generate_tokens_and_response(
                 kdf: Kdf {
                     kdf_type: user.kdf_type,
                     iterations: user.kdf_iterations,
-                    memory: None,      // Not stored currently
-                    parallelism: None, // Not stored currently
+                    memory: user.kdf_memory,
+                    parallelism: user.kdf_parallelism,
                 },
                 master_key_encrypted_user_key: user.key,
             }),
         },
+        two_factor_token: None,
     }))
 }
 
+/// The client id of a master password login, as sent by the official clients (`web`, `browser`,
+/// `cli`, ...). `user.` ids belong to the API key, which [`Claims::is_api_key`] goes by.
+fn interactive_client_id(payload: &TokenRequest) -> String {
+    payload
+        .client_id
+        .clone()
+        .filter(|client_id| !client_id.starts_with("user."))
+        .unwrap_or_else(|| "web".to_string())
+}
+
+async fn find_device(
+    db: &D1Database,
+    user_id: &str,
+    identifier: Option<&str>,
+) -> Result<Option<Device>, AppError> {
+    let Some(identifier) = identifier else {
+        return Ok(None);
+    };
+
+    query!(
+        db,
+        "SELECT * FROM devices WHERE user_id = ?1 AND identifier = ?2",
+        user_id,
+        identifier
+    )
+    .map_err(|_| AppError::Database)?
+    .first(None)
+    .await
+    .map_err(|_| AppError::Database)
+}
+
+/// Records the device a login came from, updating its details if it is already known.
+///
+/// Refresh tokens are bound to a device, so a client that doesn't identify itself gets a
+/// device of its own.
+async fn save_device(
+    db: &D1Database,
+    user_id: &str,
+    existing: Option<Device>,
+    payload: &TokenRequest,
+) -> Result<Device, AppError> {
+    let identifier = payload
+        .device_identifier
+        .clone()
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    let device_type = payload.device_type.unwrap_or(0);
+    let device_name = payload
+        .device_name
+        .clone()
+        .unwrap_or_else(|| "Unknown".to_string());
+    let push_token = payload.device_push_token.clone();
+
+    let device = match existing {
+        Some(device) => {
+            query!(
+                db,
+                "UPDATE devices SET name = ?1, type = ?2, push_token = ?3, updated_at = ?4 WHERE id = ?5",
+                device_name,
+                device_type,
+                push_token,
+                now,
+                device.id
+            )
+            .map_err(|_| AppError::Database)?
+            .run()
+            .await?;
+
+            Device {
+                name: Some(device_name),
+                r#type: device_type,
+                push_token,
+                updated_at: now,
+                ..device
+            }
+        }
+        None => {
+            let device = Device {
+                id: Uuid::new_v4().to_string(),
+                user_id: Some(user_id.to_string()),
+                identifier,
+                push_token,
+                r#type: device_type,
+                name: Some(device_name),
+                twofactor_remember: None,
+                last_active_at: None,
+                created_at: now.clone(),
+                updated_at: now,
+            };
+            query!(
+                db,
+                "INSERT INTO devices (id, user_id, identifier, type, name, push_token, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                device.id,
+                device.user_id,
+                device.identifier,
+                device.r#type,
+                device.name,
+                device.push_token,
+                device.created_at,
+                device.updated_at
+            )
+            .map_err(|_| AppError::Database)?
+            .run()
+            .await?;
+
+            device
+        }
+    };
+
+    Ok(device)
+}
+
+/// Replaces a legacy or outdated stored password hash, now that the plain client hash is known
+/// to be right.
+async fn rehash_password(
+    db: &D1Database,
+    user_id: &str,
+    master_password_hash: &str,
+) -> Result<(), AppError> {
+    let password = crypto::hash_password(master_password_hash).await?;
+    query!(
+        db,
+        "UPDATE users SET master_password_hash = ?1, password_salt = ?2, password_iterations = ?3 WHERE id = ?4",
+        password.hash,
+        password.salt,
+        password.iterations,
+        user_id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+/// Finishes a master password or SSO login once the user has passed two-step login: records
+/// the device, remembers it for two-step login if asked to and issues the tokens.
+async fn finish_login(
+    env: &Arc<Env>,
+    db: &D1Database,
+    user: User,
+    device: Option<Device>,
+    provider: Option<TwoFactorType>,
+    payload: &TokenRequest,
+) -> Result<Json<TokenResponse>, AppError> {
+    let device = save_device(db, &user.id, device, payload).await?;
+
+    // Logging in with a remember token keeps that token, so only a real second factor
+    // issues a new one.
+    let mut remember_token = None;
+    if let (Some(provider), Some(1)) = (provider, payload.two_factor_remember) {
+        if provider != TwoFactorType::Remember {
+            remember_token = Some(
+                two_factor::remember::remember_device(db, &device, &user.security_stamp).await?,
+            );
+        }
+    }
+
+    let session = Session {
+        user_id: user.id.clone(),
+        device_id: device.id.clone(),
+        client_id: interactive_client_id(payload),
+        scope: vec![SCOPE_API.to_string(), SCOPE_OFFLINE_ACCESS.to_string()],
+    };
+    let mut response = generate_tokens_and_response(db, user, &device, session, env).await?;
+    response.0.two_factor_token = remember_token;
+    Ok(response)
+}
+
 #[worker::send]
 pub async fn token(
     State(env): State<Arc<Env>>,
+    headers: HeaderMap,
     Form(payload): Form<TokenRequest>,
 ) -> Result<Json<TokenResponse>, AppError> {
     let db = db::get_db(&env)?;
@@ -157,100 +364,201 @@ pub async fn token(
         "password" => {
             let username = payload
                 .username
+                .as_deref()
                 .ok_or_else(|| AppError::BadRequest("Missing username".to_string()))?;
             let password_hash = payload
                 .password
+                .as_deref()
                 .ok_or_else(|| AppError::BadRequest("Missing password".to_string()))?;
 
-            let user: Value = db
+            let email = username.to_lowercase();
+            let ip = rate_limit::client_ip(&headers);
+            let limiter = RateLimiter::new(&db, SystemClock);
+            limiter.attempt_login(&email, &ip).await?;
+
+            let user: Option<Value> = db
                 .prepare("SELECT * FROM users WHERE email = ?1")
-                .bind(&[username.to_lowercase().into()])?
+                .bind(&[email.clone().into()])?
                 .first(None)
                 .await
-                .map_err(|_| AppError::Unauthorized("Invalid credentials".to_string()))?
-                .ok_or_else(|| AppError::Unauthorized("Invalid credentials".to_string()))?;
-            let user: User = serde_json::from_value(user).map_err(|_| AppError::Internal)?;
-            // Securely compare the provided hash with the stored hash
-            if !constant_time_eq(
-                user.master_password_hash.as_bytes(),
-                password_hash.as_bytes(),
-            ) {
-                return Err(AppError::Unauthorized("Invalid credentials".to_string()));
+                .map_err(|_| AppError::Database)?;
+            let user: Option<User> = user
+                .map(serde_json::from_value)
+                .transpose()
+                .map_err(|_| AppError::Internal)?;
+            // Before anything is checked against the account, so a disabled one can't spend
+            // its approved login request.
+            if let Some(user) = &user {
+                check_enabled(user)?;
             }
+            let authenticated = match (&user, payload.auth_request.as_deref()) {
+                (Some(user), Some(auth_request)) => {
+                    auth_requests::redeem(
+                        &db,
+                        auth_request,
+                        user,
+                        password_hash,
+                        payload.device_identifier.as_deref(),
+                    )
+                    .await?
+                }
+                // Securely compare the provided hash with the stored hash
+                (Some(user), None) => user.check_master_password(password_hash).await?,
+                (None, _) => false,
+            };
+            let user = match user {
+                Some(user) if authenticated => user,
+                _ => return Err(AppError::Unauthorized("Invalid credentials".to_string())),
+            };
 
-            if let Some(device_identifier) = &payload.device_identifier {
-                let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
-                let device_type = payload.device_type.unwrap_or(0);
-                let device_name = payload.device_name.clone().unwrap_or_else(|| "Unknown".to_string());
-                let push_token = payload.device_push_token.clone();
-                let user_id = user.id.clone();
-
-                 let device_exists_result: Result<Option<Device>, _> = query!(
-                     &db,
-                     "SELECT * FROM devices WHERE user_id = ?1 AND identifier = ?2",
-                     user_id,
-                     device_identifier
-                 )
-                 .map_err(|_| AppError::Database)?
-                 .first(None)
-                 .await;
-                 
-                 match device_exists_result {
-                    Ok(Some(_)) => {
-                        let _ = query!(
-                            &db,
-                            "UPDATE devices SET name = ?1, type = ?2, push_token = ?3, updated_at = ?4 WHERE user_id = ?5 AND identifier = ?6",
-                            device_name,
-                            device_type,
-                            push_token,
-                            now,
-                            user_id,
-                            device_identifier
-                        )
-                        .map_err(|_| AppError::Database)?
-                        .run()
-                        .await?;
-                    },
-                    Ok(None) => {
-                        let id = Uuid::new_v4().to_string();
-                        let _ = query!(
-                            &db,
-                            "INSERT INTO devices (id, user_id, identifier, type, name, push_token, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
-                            id,
-                            user_id,
-                            device_identifier,
-                            device_type,
-                            device_name,
-                            push_token,
-                            now,
-                            now
-                        )
-                        .map_err(|_| AppError::Database)?
-                        .run()
-                        .await?;
-                    },
-                    Err(e) => {
-                        console_log!("Failed to check/update device: {:?}", e);
+            let device =
+                find_device(&db, &user.id, payload.device_identifier.as_deref()).await?;
+            // A device the user approved from a signed in client doesn't need a second factor,
+            // like upstream: the approval already is one. It takes an interactive session on
+            // another device, which passed two-step login itself, and the user comparing the
+            // fingerprint there. The access code alone gets nowhere, `redeem` only accepts a
+            // request that was approved, once, and from the device that made it.
+            let provider = if payload.auth_request.is_some() {
+                None
+            } else {
+                match two_factor::validate_login(
+                    &env,
+                    &db,
+                    &user,
+                    device.as_ref(),
+                    payload.two_factor_provider,
+                    payload.two_factor_token.as_deref(),
+                )
+                .await
+                {
+                    // A wrong code counts like a wrong password, the challenge itself doesn't.
+                    Err(err @ AppError::TwoFactorRequired(_)) => {
+                        limiter.refund_login(&email, &ip).await?;
+                        return Err(err);
                     }
-                 }
+                    result => result?,
+                }
+            };
+            limiter.login_succeeded(&email, &ip).await?;
+
+            if payload.auth_request.is_none() && user.needs_password_rehash() {
+                rehash_password(&db, &user.id, password_hash).await?;
+            }
+
+            finish_login(&env, &db, user, device, provider, &payload).await
+        }
+        // Single sign-on, with the code our SSO callback handed to the client.
+        "authorization_code" => {
+            let code = payload
+                .code
+                .as_deref()
+                .ok_or_else(|| AppError::BadRequest("Missing code".to_string()))?;
+            let code_verifier = payload
+                .code_verifier
+                .as_deref()
+                .ok_or_else(|| AppError::BadRequest("Missing code_verifier".to_string()))?;
+            let redirect_uri = payload
+                .redirect_uri
+                .as_deref()
+                .ok_or_else(|| AppError::BadRequest("Missing redirect_uri".to_string()))?;
+
+            let ip = rate_limit::client_ip(&headers);
+            let limiter = RateLimiter::new(&db, SystemClock);
+            limiter.attempt(&LOGIN_BY_IP, &ip).await?;
+
+            let (user, sso_code) =
+                sso::redeem_code(&db, code, code_verifier, redirect_uri).await?;
+            check_enabled(&user)?;
+
+            let device =
+                find_device(&db, &user.id, payload.device_identifier.as_deref()).await?;
+            let provider = match two_factor::validate_login(
+                &env,
+                &db,
+                &user,
+                device.as_ref(),
+                payload.two_factor_provider,
+                payload.two_factor_token.as_deref(),
+            )
+            .await
+            {
+                Err(err @ AppError::TwoFactorRequired(_)) => {
+                    limiter.refund(&LOGIN_BY_IP, &ip).await?;
+                    return Err(err);
+                }
+                result => result?,
+            };
+            limiter.refund(&LOGIN_BY_IP, &ip).await?;
+            // Only now, the code is sent again with the second factor after the challenge.
+            sso::consume_code(&db, &sso_code).await?;
+
+            finish_login(&env, &db, user, device, provider, &payload).await
+        }
+        // Personal API key login, used by the bw CLI. The key stands in for both the master
+        // password and the second factor, like it does upstream.
+        "client_credentials" => {
+            if payload.scope.as_deref() != Some("api") {
+                return Err(AppError::BadRequest("Unsupported scope".to_string()));
+            }
+            let client_id = payload
+                .client_id
+                .as_deref()
+                .ok_or_else(|| AppError::BadRequest("Missing client_id".to_string()))?;
+            let client_secret = payload
+                .client_secret
+                .as_deref()
+                .ok_or_else(|| AppError::BadRequest("Missing client_secret".to_string()))?;
+            if payload.device_identifier.is_none() {
+                return Err(AppError::BadRequest("Missing device_identifier".to_string()));
             }
 
-            generate_tokens_and_response(user, &env)
+            let ip = rate_limit::client_ip(&headers);
+            let limiter = RateLimiter::new(&db, SystemClock);
+            limiter.attempt_login(client_id, &ip).await?;
+
+            let user: Option<User> = match client_id.strip_prefix("user.") {
+                Some(user_id) => query!(&db, "SELECT * FROM users WHERE id = ?1", user_id)
+                    .map_err(|_| AppError::Database)?
+                    .first(None)
+                    .await
+                    .map_err(|_| AppError::Database)?,
+                None => None,
+            };
+            let user = match user {
+                Some(user)
+                    if user.api_key.as_ref().is_some_and(|api_key| {
+                        constant_time_eq(api_key.as_bytes(), client_secret.as_bytes())
+                    }) =>
+                {
+                    user
+                }
+                _ => return Err(AppError::Unauthorized("Invalid credentials".to_string())),
+            };
+            check_enabled(&user)?;
+            limiter.login_succeeded(client_id, &ip).await?;
+
+            let device =
+                find_device(&db, &user.id, payload.device_identifier.as_deref()).await?;
+            let device = save_device(&db, &user.id, device, &payload).await?;
+
+            let session = Session {
+                user_id: user.id.clone(),
+                device_id: device.id.clone(),
+                client_id: client_id.to_string(),
+                scope: vec![SCOPE_API.to_string()],
+            };
+            generate_tokens_and_response(&db, user, &device, session, &env).await
         }
         "refresh_token" => {
             let refresh_token = payload
                 .refresh_token
                 .ok_or_else(|| AppError::BadRequest("Missing refresh_token".to_string()))?;
 
-            let jwt_refresh_secret = env.secret("JWT_REFRESH_SECRET")?.to_string();
-            let token_data = decode::<Claims>(
-                &refresh_token,
-                &DecodingKey::from_secret(jwt_refresh_secret.as_ref()),
-                &Validation::default(),
-            )
-            .map_err(|_| AppError::Unauthorized("Invalid refresh token".to_string()))?;
+            let invalid = || AppError::Unauthorized("Invalid refresh token".to_string());
 
-            let user_id = token_data.claims.sub;
+            let user_id = refresh_token::owner(&db, &refresh_token)
+                .await?
+                .ok_or_else(invalid)?;
             let user: Value = db
                 .prepare("SELECT * FROM users WHERE id = ?1")
                 .bind(&[user_id.into()])?
@@ -259,9 +567,28 @@ pub async fn token(
                 .map_err(|_| AppError::Unauthorized("Invalid user".to_string()))?
                 .ok_or_else(|| AppError::Unauthorized("Invalid user".to_string()))?;
             let user: User = serde_json::from_value(user).map_err(|_| AppError::Internal)?;
+            // Before the token is spent, so a disabled user's token stays as it was.
+            check_enabled(&user)?;
 
-            generate_tokens_and_response(user, &env)
+            let session = refresh_token::redeem(&db, &refresh_token).await?;
+            if session.user_id != user.id {
+                return Err(invalid());
+            }
+            let device: Device = query!(&db, "SELECT * FROM devices WHERE id = ?1", session.device_id)
+                .map_err(|_| AppError::Database)?
+                .first(None)
+                .await
+                .map_err(|_| AppError::Database)?
+                .ok_or_else(|| AppError::Unauthorized("Invalid refresh token".to_string()))?;
+
+            generate_tokens_and_response(&db, user, &device, session, &env).await
         }
         _ => Err(AppError::BadRequest("Unsupported grant_type".to_string())),
     }
-}
\ No newline at end of file
+}
+
+/// Publishes the keys access tokens are signed with, for services that verify them.
+#[worker::send]
+pub async fn jwks(State(env): State<Arc<Env>>) -> Result<Json<Value>, AppError> {
+    Ok(Json(jwt::jwks(&env)?))
+}
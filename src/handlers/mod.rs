@@ -1,4 +1,6 @@
 pub mod accounts;
+pub mod admin;
+pub mod admin_panel;
 pub mod ciphers;
 pub mod config;
 pub mod identity;
@@ -6,3 +8,6 @@ pub mod sync;
 pub mod folders;
 pub mod import;
 pub mod devices;
+pub mod two_factor;
+pub mod auth_requests;
+pub mod sso;
@@ -1,12 +1,13 @@
 use axum::{extract::State, Json};
 use serde_json::Value;
 use std::sync::Arc;
-use worker::Env;
+use worker::{D1Database, Env};
 
 use crate::{
     auth::Claims,
     db,
     error::AppError,
+    handlers::two_factor,
     models::{
         cipher::{Cipher, CipherDBModel},
         folder::{Folder, FolderResponse},
@@ -15,6 +16,33 @@ use crate::{
     },
 };
 
+/// The user's profile, as returned by sync and the profile endpoints.
+pub async fn build_profile(db: &D1Database, user: User) -> Result<Profile, AppError> {
+    let two_factor_enabled = two_factor::is_enabled(db, &user.id).await?;
+
+    let time = chrono::DateTime::parse_from_rfc3339(&user.created_at)
+        .map_err(|_| AppError::Internal)?
+        .to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+    Ok(Profile {
+        id: user.id,
+        name: user.name,
+        email: user.email,
+        master_password_hint: user.master_password_hint,
+        security_stamp: user.security_stamp,
+        object: "profile".to_string(),
+        premium: true,
+        premium_from_organization: false,
+        email_verified: user.email_verified,
+        force_password_reset: false,
+        two_factor_enabled,
+        uses_key_connector: false,
+        creation_date: time,
+        key: user.key,
+        private_key: user.private_key,
+        avatar_color: user.avatar_color,
+    })
+}
+
 #[worker::send]
 pub async fn get_sync_data(
     claims: Claims,
@@ -63,26 +91,7 @@ pub async fn get_sync_data(
         .map(|cipher| cipher.into())
         .collect::<Vec<Cipher>>();
 
-    let time = chrono::DateTime::parse_from_rfc3339(&user.created_at)
-        .map_err(|_| AppError::Internal)?
-        .to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
-    let profile = Profile {
-        id: user.id,
-        name: user.name,
-        email: user.email,
-        master_password_hint: user.master_password_hint,
-        security_stamp: user.security_stamp,
-        object: "profile".to_string(),
-        premium: true,
-        premium_from_organization: false,
-        email_verified: true,
-        force_password_reset: false,
-        two_factor_enabled: false,
-        uses_key_connector: false,
-        creation_date: time,
-        key: user.key,
-        private_key: user.private_key,
-    };
+    let profile = build_profile(&db, user).await?;
 
     let response = SyncResponse {
         profile,
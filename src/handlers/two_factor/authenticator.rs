@@ -0,0 +1,295 @@
+use axum::{extract::State, Json};
+use chrono::Utc;
+use ring::hmac;
+use std::sync::Arc;
+use worker::{query, D1Database, Env};
+
+use super::{check_password, delete_provider, get_two_factor, recover, save_two_factor};
+use crate::{
+    auth::Claims,
+    db,
+    error::AppError,
+    models::two_factor::{
+        AuthenticatorResponse, DisableTwoFactorData, EnableAuthenticatorData, PasswordData,
+        TwoFactor, TwoFactorProviderResponse, TwoFactorType,
+    },
+};
+
+// RFC 6238 parameters used by every Bitwarden client.
+const TOTP_STEP_SECONDS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+// Number of steps of clock drift accepted in each direction.
+const TOTP_SKEW_STEPS: i64 = 1;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub(super) fn encode_base32(bytes: &[u8]) -> String {
+    let mut output = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            output.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        output.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    output
+}
+
+fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    // Authenticator apps display the key in groups and sometimes in lowercase.
+    for c in input.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a as char == c.to_ascii_uppercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(output)
+}
+
+fn totp_code(secret: &[u8], step: i64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &(step as u64).to_be_bytes());
+    let hash = tag.as_ref();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    binary % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Checks `code` against the secret and returns the matching time step.
+///
+/// Steps at or before `last_used` are rejected so a code can't be replayed.
+fn verify_totp(secret: &[u8], code: &str, last_used: i64, now: i64) -> Option<i64> {
+    let code: u32 = code.trim().parse().ok()?;
+    let current = now / TOTP_STEP_SECONDS;
+
+    (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS)
+        .find(|&step| step > last_used && totp_code(secret, step) == code)
+}
+
+fn new_secret() -> String {
+    encode_base32(&rand::random::<[u8; 20]>())
+}
+
+#[worker::send]
+pub async fn get_authenticator(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<PasswordData>,
+) -> Result<Json<AuthenticatorResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    // The secret is only stored once the user proves they enrolled it with a valid code.
+    let response = match get_two_factor(&db, &user.id, TwoFactorType::Authenticator).await? {
+        Some(tf) => AuthenticatorResponse {
+            enabled: true,
+            key: tf.data,
+            object: "twoFactorAuthenticator".to_string(),
+        },
+        None => AuthenticatorResponse {
+            enabled: false,
+            key: new_secret(),
+            object: "twoFactorAuthenticator".to_string(),
+        },
+    };
+
+    Ok(Json(response))
+}
+
+#[worker::send]
+pub async fn activate_authenticator(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<EnableAuthenticatorData>,
+) -> Result<Json<AuthenticatorResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    let key: String = payload
+        .key
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    let secret = decode_base32(&key)
+        .filter(|secret| secret.len() >= 10)
+        .ok_or_else(|| AppError::BadRequest("Invalid authenticator key".to_string()))?;
+
+    let step = verify_totp(&secret, &payload.token, 0, Utc::now().timestamp())
+        .ok_or_else(|| AppError::BadRequest("Invalid TOTP code".to_string()))?;
+
+    save_two_factor(&db, &user.id, TwoFactorType::Authenticator, &key, step).await?;
+    recover::ensure_recovery_code(&db, &user).await?;
+
+    Ok(Json(AuthenticatorResponse {
+        enabled: true,
+        key,
+        object: "twoFactorAuthenticator".to_string(),
+    }))
+}
+
+#[worker::send]
+pub async fn disable_authenticator(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<DisableTwoFactorData>,
+) -> Result<Json<TwoFactorProviderResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    delete_provider(&db, &user.id, TwoFactorType::Authenticator).await?;
+
+    Ok(Json(TwoFactorProviderResponse {
+        enabled: false,
+        r#type: TwoFactorType::Authenticator as i32,
+        object: "twoFactorProvider".to_string(),
+    }))
+}
+
+/// Validates a TOTP code sent with a login and records its step so it can't be reused.
+pub async fn validate_login_code(
+    db: &D1Database,
+    two_factor: &TwoFactor,
+    code: &str,
+) -> Result<(), AppError> {
+    let secret = decode_base32(&two_factor.data).ok_or(AppError::Internal)?;
+    let step = verify_totp(&secret, code, two_factor.last_used, Utc::now().timestamp())
+        .ok_or_else(|| AppError::BadRequest("Two-step token is invalid. Try again.".to_string()))?;
+
+    // Guard against two concurrent logins racing with the same code.
+    let result = query!(
+        db,
+        "UPDATE twofactor SET last_used = ?1 WHERE id = ?2 AND last_used < ?1",
+        step,
+        two_factor.id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    let changes = result.meta()?.and_then(|meta| meta.changes).unwrap_or(0);
+    if changes == 0 {
+        return Err(AppError::BadRequest(
+            "Two-step token is invalid. Try again.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA-1 secret of RFC 6238's test vectors.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn totp_matches_rfc_6238_vectors() {
+        // Appendix B, cut down from eight digits to the last six.
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            let step = time / TOTP_STEP_SECONDS;
+            assert_eq!(verify_totp(RFC_SECRET, code, 0, time), Some(step), "{time}");
+        }
+    }
+
+    #[test]
+    fn totp_accepts_one_step_of_skew() {
+        let now = 1111111109;
+        let current = now / TOTP_STEP_SECONDS;
+        let code = |step: i64| format!("{:06}", totp_code(RFC_SECRET, step));
+
+        assert_eq!(
+            verify_totp(RFC_SECRET, &code(current - 1), 0, now),
+            Some(current - 1)
+        );
+        assert_eq!(
+            verify_totp(RFC_SECRET, &code(current + 1), 0, now),
+            Some(current + 1)
+        );
+        assert_eq!(verify_totp(RFC_SECRET, &code(current - 2), 0, now), None);
+        assert_eq!(verify_totp(RFC_SECRET, &code(current + 2), 0, now), None);
+    }
+
+    #[test]
+    fn totp_rejects_used_steps() {
+        let now = 1111111109;
+        let current = now / TOTP_STEP_SECONDS;
+        let code = format!("{:06}", totp_code(RFC_SECRET, current));
+
+        assert_eq!(
+            verify_totp(RFC_SECRET, &code, current - 1, now),
+            Some(current)
+        );
+        assert_eq!(verify_totp(RFC_SECRET, &code, current, now), None);
+        assert_eq!(verify_totp(RFC_SECRET, "not a code", 0, now), None);
+    }
+
+    #[test]
+    fn base32_decodes_rfc_4648_vectors() {
+        for (encoded, decoded) in [
+            ("", ""),
+            ("MY======", "f"),
+            ("MZXQ====", "fo"),
+            ("MZXW6===", "foo"),
+            ("MZXW6YQ=", "foob"),
+            ("MZXW6YTB", "fooba"),
+            ("MZXW6YTBOI======", "foobar"),
+        ] {
+            assert_eq!(decode_base32(encoded), Some(decoded.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn base32_accepts_what_apps_display() {
+        let expected = Some(b"foobar".to_vec());
+        assert_eq!(decode_base32("MZXW6YTBOI"), expected);
+        assert_eq!(decode_base32("mzxw6ytboi"), expected);
+        assert_eq!(decode_base32("mzxw 6ytb oi== ===="), expected);
+        assert_eq!(decode_base32("MZXW1YTBOI"), None);
+    }
+
+    #[test]
+    fn base32_round_trips() {
+        let secret = rand::random::<[u8; 20]>();
+        assert_eq!(
+            decode_base32(&encode_base32(&secret)),
+            Some(secret.to_vec())
+        );
+    }
+}
@@ -0,0 +1,392 @@
+use axum::{extract::State, http::HeaderMap, Json};
+use chrono::Utc;
+use constant_time_eq::constant_time_eq;
+use rand::Rng;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use worker::{query, D1Database, Env};
+
+use super::{check_password, delete_provider, get_two_factor, recover, save_two_factor};
+use crate::{
+    auth::{self, Claims},
+    db,
+    error::AppError,
+    mail::{Email, Mailer},
+    models::{
+        two_factor::{
+            EmailResponse, EmailTokenData, EnableEmailData, PasswordData, SendEmailData, TwoFactor,
+            TwoFactorType,
+        },
+        user::User,
+    },
+    rate_limit::{self, RateLimiter, SystemClock},
+};
+
+// How long an emailed code stays valid.
+const TOKEN_TTL_SECONDS: i64 = 10 * 60;
+// Wrong guesses allowed before the code is thrown away.
+const MAX_ATTEMPTS: u32 = 3;
+
+fn new_token() -> String {
+    format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+}
+
+/// Hides most of the local part, e.g. `jo*****@example.com`.
+fn obfuscate_email(email: &str) -> String {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            let shown: String = local.chars().take(2).collect();
+            let hidden = local.chars().count().saturating_sub(2);
+            format!("{shown}{}@{domain}", "*".repeat(hidden))
+        }
+        None => email.to_string(),
+    }
+}
+
+fn parse_data(two_factor: &TwoFactor) -> Result<EmailTokenData, AppError> {
+    serde_json::from_str(&two_factor.data).map_err(|_| AppError::Internal)
+}
+
+/// Checks a code against the stored data, counting failed attempts.
+fn check_token(data: &mut EmailTokenData, token: &str) -> Result<(), AppError> {
+    let invalid = || AppError::BadRequest("Two-step token is invalid. Try again.".to_string());
+    let Some(expected) = data.last_token.clone() else {
+        return Err(invalid());
+    };
+
+    if Utc::now().timestamp() - data.token_sent > TOKEN_TTL_SECONDS {
+        data.last_token = None;
+        return Err(AppError::BadRequest(
+            "Two-step token has expired. Request a new one.".to_string(),
+        ));
+    }
+
+    if !constant_time_eq(expected.as_bytes(), token.trim().as_bytes()) {
+        data.attempts += 1;
+        if data.attempts >= MAX_ATTEMPTS {
+            data.last_token = None;
+        }
+        return Err(invalid());
+    }
+
+    data.last_token = None;
+    data.attempts = 0;
+    Ok(())
+}
+
+async fn send_token(
+    mailer: &Mailer,
+    data: &mut EmailTokenData,
+    subject: &str,
+) -> Result<(), AppError> {
+    let token = new_token();
+    let email = Email {
+        to: data.email.clone(),
+        subject: subject.to_string(),
+        body: format!(
+            "Your two-step verification code is: {token}\n\n\
+             The code expires in {} minutes. If you did not try to log in, \
+             change your master password.",
+            TOKEN_TTL_SECONDS / 60
+        ),
+    };
+    mailer.send(&email).await?;
+
+    data.last_token = Some(token);
+    data.token_sent = Utc::now().timestamp();
+    data.attempts = 0;
+    Ok(())
+}
+
+async fn save_data(
+    db: &D1Database,
+    user_id: &str,
+    r#type: TwoFactorType,
+    data: &EmailTokenData,
+) -> Result<(), AppError> {
+    let data = serde_json::to_string(data).map_err(|_| AppError::Internal)?;
+    save_two_factor(db, user_id, r#type, &data, 0).await
+}
+
+#[worker::send]
+pub async fn get_email(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<PasswordData>,
+) -> Result<Json<EmailResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    let response = match get_two_factor(&db, &user.id, TwoFactorType::Email).await? {
+        Some(tf) => EmailResponse {
+            email: Some(parse_data(&tf)?.email),
+            enabled: true,
+            object: "twoFactorEmail".to_string(),
+        },
+        None => EmailResponse {
+            email: None,
+            enabled: false,
+            object: "twoFactorEmail".to_string(),
+        },
+    };
+
+    Ok(Json(response))
+}
+
+/// Sends the code that proves the user can read the address they are enabling.
+#[worker::send]
+pub async fn send_email_setup(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<SendEmailData>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    let mut data = EmailTokenData {
+        email: payload.email.trim().to_lowercase(),
+        last_token: None,
+        token_sent: 0,
+        attempts: 0,
+    };
+    send_token(
+        &Mailer::required(&env)?,
+        &mut data,
+        "Verify your two-step login email",
+    )
+    .await?;
+    save_data(
+        &db,
+        &user.id,
+        TwoFactorType::EmailVerificationChallenge,
+        &data,
+    )
+    .await?;
+
+    Ok(Json(()))
+}
+
+#[worker::send]
+pub async fn activate_email(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<EnableEmailData>,
+) -> Result<Json<EmailResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    let pending = get_two_factor(&db, &user.id, TwoFactorType::EmailVerificationChallenge)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("No verification code was sent".to_string()))?;
+    let mut data = parse_data(&pending)?;
+    if data.email != payload.email.trim().to_lowercase() {
+        return Err(AppError::BadRequest(
+            "Email does not match the one the code was sent to".to_string(),
+        ));
+    }
+
+    if let Err(err) = check_token(&mut data, &payload.token) {
+        save_data(
+            &db,
+            &user.id,
+            TwoFactorType::EmailVerificationChallenge,
+            &data,
+        )
+        .await?;
+        return Err(err);
+    }
+
+    save_data(&db, &user.id, TwoFactorType::Email, &data).await?;
+    delete_provider(&db, &user.id, TwoFactorType::EmailVerificationChallenge).await?;
+    recover::ensure_recovery_code(&db, &user).await?;
+
+    Ok(Json(EmailResponse {
+        email: Some(data.email),
+        enabled: true,
+        object: "twoFactorEmail".to_string(),
+    }))
+}
+
+/// Resends the login code. The user isn't logged in yet, so the master password proves who
+/// is asking.
+#[worker::send]
+pub async fn send_email_login(
+    State(env): State<Arc<Env>>,
+    headers: HeaderMap,
+    Json(payload): Json<SendEmailData>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let email = payload.email.to_lowercase();
+    let ip = rate_limit::client_ip(&headers);
+    let limiter = RateLimiter::new(&db, SystemClock);
+    limiter.attempt_login(&email, &ip).await?;
+
+    let user: Option<User> = query!(&db, "SELECT * FROM users WHERE email = ?1", email)
+        .map_err(|_| AppError::Database)?
+        .first(None)
+        .await
+        .map_err(|_| AppError::Database)?;
+    let user = match user {
+        Some(user)
+            if user
+                .check_master_password(&payload.master_password_hash)
+                .await? =>
+        {
+            user
+        }
+        _ => {
+            return Err(AppError::BadRequest(
+                "Username or password is incorrect".to_string(),
+            ))
+        }
+    };
+    if !user.enabled {
+        return Err(auth::account_disabled());
+    }
+    limiter.refund_login(&email, &ip).await?;
+
+    let two_factor = get_two_factor(&db, &user.id, TwoFactorType::Email)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("Email two-step login is not enabled".to_string()))?;
+    send_login_token(&env, &db, &two_factor).await?;
+
+    Ok(Json(()))
+}
+
+pub async fn send_login_token(
+    env: &Env,
+    db: &D1Database,
+    two_factor: &TwoFactor,
+) -> Result<(), AppError> {
+    let mut data = parse_data(two_factor)?;
+    send_token(
+        &Mailer::required(env)?,
+        &mut data,
+        "Your two-step login code",
+    )
+    .await?;
+    save_data(db, &two_factor.user_id, TwoFactorType::Email, &data).await
+}
+
+/// The `TwoFactorProviders2` entry of the login challenge.
+pub fn login_challenge(two_factor: &TwoFactor) -> Result<Value, AppError> {
+    let data = parse_data(two_factor)?;
+    Ok(json!({ "Email": obfuscate_email(&data.email) }))
+}
+
+pub async fn validate_login_token(
+    db: &D1Database,
+    two_factor: &TwoFactor,
+    token: &str,
+) -> Result<(), AppError> {
+    let mut data = parse_data(two_factor)?;
+    let result = check_token(&mut data, token);
+    save_data(db, &two_factor.user_id, TwoFactorType::Email, &data).await?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        cell::RefCell,
+        future::Future,
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    /// Runs a future that never waits, which is all a [`Mailer::Memory`] send does.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future waited"),
+        }
+    }
+
+    fn new_data() -> EmailTokenData {
+        EmailTokenData {
+            email: "alice@example.com".to_string(),
+            last_token: None,
+            token_sent: 0,
+            attempts: 0,
+        }
+    }
+
+    /// Sends a code and returns it as read from the mail.
+    fn send(mailer: &Mailer, data: &mut EmailTokenData) -> String {
+        block_on(send_token(mailer, data, "Your two-step login code")).unwrap();
+        let Mailer::Memory(outbox) = mailer else {
+            unreachable!()
+        };
+        let email = outbox.borrow().last().cloned().unwrap();
+        assert_eq!(email.to, "alice@example.com");
+        let code = email.body.split_whitespace().nth(5).unwrap();
+        assert_eq!(Some(code), data.last_token.as_deref());
+        code.to_string()
+    }
+
+    fn wrong(code: &str) -> String {
+        format!("{:06}", (code.parse::<u32>().unwrap() + 1) % 1_000_000)
+    }
+
+    #[test]
+    fn accepts_the_mailed_code_once() {
+        let mailer = Mailer::Memory(RefCell::new(Vec::new()));
+        let mut data = new_data();
+        let code = send(&mailer, &mut data);
+
+        assert!(check_token(&mut data, &format!(" {code}\n")).is_ok());
+        assert_eq!(data.last_token, None);
+        assert!(check_token(&mut data, &code).is_err());
+    }
+
+    #[test]
+    fn rejects_expired_code() {
+        let mailer = Mailer::Memory(RefCell::new(Vec::new()));
+        let mut data = new_data();
+        let code = send(&mailer, &mut data);
+        data.token_sent -= TOKEN_TTL_SECONDS + 1;
+
+        assert!(check_token(&mut data, &code).is_err());
+        assert_eq!(data.last_token, None);
+    }
+
+    #[test]
+    fn throws_the_code_away_after_three_wrong_guesses() {
+        let mailer = Mailer::Memory(RefCell::new(Vec::new()));
+        let mut data = new_data();
+        let code = send(&mailer, &mut data);
+
+        for attempts in 1..MAX_ATTEMPTS {
+            assert!(check_token(&mut data, &wrong(&code)).is_err());
+            assert_eq!(data.attempts, attempts);
+        }
+        // The last guess left is the right code.
+        let mut spare = data.clone();
+        assert!(check_token(&mut spare, &code).is_ok());
+
+        assert!(check_token(&mut data, &wrong(&code)).is_err());
+        assert_eq!(data.last_token, None);
+        assert!(check_token(&mut data, &code).is_err());
+    }
+
+    #[test]
+    fn resending_replaces_the_code() {
+        let mailer = Mailer::Memory(RefCell::new(Vec::new()));
+        let mut data = new_data();
+        let first = send(&mailer, &mut data);
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(check_token(&mut data, &wrong(&first)).is_err());
+        }
+
+        let second = send(&mailer, &mut data);
+        assert_eq!(data.attempts, 0);
+        if first != second {
+            assert!(check_token(&mut data.clone(), &first).is_err());
+        }
+        assert!(check_token(&mut data, &second).is_ok());
+    }
+}
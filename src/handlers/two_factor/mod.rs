@@ -0,0 +1,254 @@
+use axum::{extract::State, Json};
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use uuid::Uuid;
+use worker::{query, D1Database, Env};
+
+use crate::{
+    auth::Claims,
+    db,
+    error::AppError,
+    models::{
+        device::Device,
+        two_factor::{
+            DisableTwoFactorData, TwoFactor, TwoFactorListResponse, TwoFactorProviderResponse,
+            TwoFactorType,
+        },
+        user::User,
+    },
+};
+
+pub mod authenticator;
+pub mod email;
+pub mod recover;
+pub mod remember;
+pub mod webauthn;
+
+/// Returns every two-factor provider the user has enabled.
+pub async fn get_two_factors(db: &D1Database, user_id: &str) -> Result<Vec<TwoFactor>, AppError> {
+    let two_factors: Vec<TwoFactor> =
+        query!(db, "SELECT * FROM twofactor WHERE user_id = ?1", user_id)
+            .map_err(|_| AppError::Database)?
+            .all()
+            .await?
+            .results()?;
+
+    Ok(two_factors
+        .into_iter()
+        .filter(|tf| TwoFactorType::from_i32(tf.r#type).is_some_and(TwoFactorType::is_provider))
+        .collect())
+}
+
+pub async fn is_enabled(db: &D1Database, user_id: &str) -> Result<bool, AppError> {
+    Ok(!get_two_factors(db, user_id).await?.is_empty())
+}
+
+pub(crate) async fn check_password(
+    user: &User,
+    master_password_hash: &str,
+) -> Result<(), AppError> {
+    if !user.check_master_password(master_password_hash).await? {
+        return Err(AppError::BadRequest("Invalid password".to_string()));
+    }
+    Ok(())
+}
+
+pub(crate) async fn get_two_factor(
+    db: &D1Database,
+    user_id: &str,
+    r#type: TwoFactorType,
+) -> Result<Option<TwoFactor>, AppError> {
+    query!(
+        db,
+        "SELECT * FROM twofactor WHERE user_id = ?1 AND type = ?2",
+        user_id,
+        r#type as i32
+    )
+    .map_err(|_| AppError::Database)?
+    .first(None)
+    .await
+    .map_err(|_| AppError::Database)
+}
+
+/// Inserts or replaces the user's row of the given type.
+pub(crate) async fn save_two_factor(
+    db: &D1Database,
+    user_id: &str,
+    r#type: TwoFactorType,
+    data: &str,
+    last_used: i64,
+) -> Result<(), AppError> {
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    query!(
+        db,
+        "INSERT INTO twofactor (id, user_id, type, data, last_used, created_at, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+         ON CONFLICT (user_id, type) DO UPDATE SET data = ?4, last_used = ?5, updated_at = ?7",
+        Uuid::new_v4().to_string(),
+        user_id,
+        r#type as i32,
+        data,
+        last_used,
+        now,
+        now
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn delete_provider(
+    db: &D1Database,
+    user_id: &str,
+    provider: TwoFactorType,
+) -> Result<(), AppError> {
+    query!(
+        db,
+        "DELETE FROM twofactor WHERE user_id = ?1 AND type = ?2",
+        user_id,
+        provider as i32
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+#[worker::send]
+pub async fn get_providers(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+) -> Result<Json<TwoFactorListResponse>, AppError> {
+    let db = db::get_db(&env)?;
+
+    let data = get_two_factors(&db, &claims.sub)
+        .await?
+        .into_iter()
+        .map(|tf| TwoFactorProviderResponse {
+            enabled: true,
+            r#type: tf.r#type,
+            object: "twoFactorProvider".to_string(),
+        })
+        .collect();
+
+    Ok(Json(TwoFactorListResponse {
+        data,
+        object: "list".to_string(),
+        continuation_token: None,
+    }))
+}
+
+#[worker::send]
+pub async fn disable(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<DisableTwoFactorData>,
+) -> Result<Json<TwoFactorProviderResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    let provider = TwoFactorType::from_i32(payload.r#type)
+        .filter(|provider| provider.is_provider())
+        .ok_or_else(|| AppError::BadRequest("Invalid two factor provider".to_string()))?;
+    delete_provider(&db, &user.id, provider).await?;
+
+    Ok(Json(TwoFactorProviderResponse {
+        enabled: false,
+        r#type: payload.r#type,
+        object: "twoFactorProvider".to_string(),
+    }))
+}
+
+/// Builds the `TwoFactorProviders` challenge the clients expect when a second factor is needed.
+async fn challenge(
+    env: &Env,
+    db: &D1Database,
+    two_factors: &[TwoFactor],
+) -> Result<AppError, AppError> {
+    let mut providers = Vec::new();
+    let mut providers2 = Map::new();
+
+    for tf in two_factors {
+        let details = match TwoFactorType::from_i32(tf.r#type) {
+            Some(TwoFactorType::Authenticator) => Value::Null,
+            Some(TwoFactorType::Email) => {
+                // With other providers around the client asks for the code once the user
+                // picks email, through `send-email-login`.
+                if two_factors.len() == 1 {
+                    email::send_login_token(env, db, tf).await?;
+                }
+                email::login_challenge(tf)?
+            }
+            Some(TwoFactorType::Webauthn) => webauthn::login_challenge(env, db, tf).await?,
+            _ => continue,
+        };
+        providers.push(tf.r#type.to_string());
+        providers2.insert(tf.r#type.to_string(), details);
+    }
+
+    Ok(AppError::TwoFactorRequired(json!({
+        "error": "invalid_grant",
+        "error_description": "Two factor required.",
+        "TwoFactorProviders": providers,
+        "TwoFactorProviders2": providers2,
+        "MasterPasswordPolicy": {
+            "Object": "masterPasswordPolicy"
+        }
+    })))
+}
+
+/// Checks the second factor of a password login.
+///
+/// Users without any provider pass straight through. Otherwise the request must carry a valid
+/// `twoFactorToken` for one of the enabled providers, or a remember token issued to `device`,
+/// or the login challenge is returned. On success the provider that was used is returned, or
+/// `None` when the user has no two-step login.
+pub async fn validate_login(
+    env: &Env,
+    db: &D1Database,
+    user: &User,
+    device: Option<&Device>,
+    provider: Option<i32>,
+    token: Option<&str>,
+) -> Result<Option<TwoFactorType>, AppError> {
+    let two_factors = get_two_factors(db, &user.id).await?;
+    if two_factors.is_empty() {
+        return Ok(None);
+    }
+
+    let (Some(provider), Some(token)) = (provider, token) else {
+        return Err(challenge(env, db, &two_factors).await?);
+    };
+
+    let two_factor = two_factors.iter().find(|tf| tf.r#type == provider);
+    let provider = TwoFactorType::from_i32(provider);
+    match (provider, two_factor) {
+        (Some(TwoFactorType::Authenticator), Some(tf)) => {
+            authenticator::validate_login_code(db, tf, token).await?
+        }
+        (Some(TwoFactorType::Email), Some(tf)) => {
+            email::validate_login_token(db, tf, token).await?
+        }
+        (Some(TwoFactorType::Webauthn), Some(tf)) => {
+            webauthn::validate_login_assertion(env, db, tf, token).await?
+        }
+        // A stale remember token isn't an error, the client just falls back to the challenge.
+        (Some(TwoFactorType::Remember), _) => {
+            if !remember::check_token(device, &user.security_stamp, token) {
+                return Err(challenge(env, db, &two_factors).await?);
+            }
+        }
+        _ => {
+            return Err(AppError::BadRequest(
+                "Invalid two factor provider".to_string(),
+            ))
+        }
+    }
+
+    Ok(provider)
+}
@@ -0,0 +1,133 @@
+use axum::{extract::State, http::HeaderMap, Json};
+use chrono::Utc;
+use constant_time_eq::constant_time_eq;
+use std::sync::Arc;
+use worker::{query, D1Database, Env};
+
+use super::{authenticator::encode_base32, check_password};
+use crate::{
+    auth::{self, Claims},
+    db,
+    error::AppError,
+    models::{
+        two_factor::{PasswordData, RecoverResponse, RecoverTwoFactorData},
+        user::User,
+    },
+    rate_limit::{self, RateLimiter, SystemClock},
+};
+
+/// Returns the user's recovery code, creating one the first time it is needed.
+///
+/// Called whenever a provider is enabled, so the code exists before the user can be locked out.
+pub(crate) async fn ensure_recovery_code(db: &D1Database, user: &User) -> Result<String, AppError> {
+    if let Some(code) = &user.totp_recover {
+        return Ok(code.clone());
+    }
+
+    let code = encode_base32(&rand::random::<[u8; 20]>());
+    query!(
+        db,
+        "UPDATE users SET totp_recover = ?1 WHERE id = ?2",
+        code,
+        user.id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok(code)
+}
+
+#[worker::send]
+pub async fn get_recover(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<PasswordData>,
+) -> Result<Json<RecoverResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    Ok(Json(RecoverResponse {
+        code: ensure_recovery_code(&db, &user).await?,
+        object: "twoFactorRecover".to_string(),
+    }))
+}
+
+/// Turns off two-step login with the recovery code. The user is locked out at this point, so
+/// the email and master password identify them instead of a session.
+#[worker::send]
+pub async fn recover(
+    State(env): State<Arc<Env>>,
+    headers: HeaderMap,
+    Json(payload): Json<RecoverTwoFactorData>,
+) -> Result<Json<()>, AppError> {
+    let db = db::get_db(&env)?;
+    let email = payload.email.to_lowercase();
+    let ip = rate_limit::client_ip(&headers);
+    let limiter = RateLimiter::new(&db, SystemClock);
+    limiter.attempt_login(&email, &ip).await?;
+
+    let user: Option<User> = query!(&db, "SELECT * FROM users WHERE email = ?1", email)
+        .map_err(|_| AppError::Database)?
+        .first(None)
+        .await
+        .map_err(|_| AppError::Database)?;
+    let user = match user {
+        Some(user)
+            if user
+                .check_master_password(&payload.master_password_hash)
+                .await? =>
+        {
+            user
+        }
+        _ => {
+            return Err(AppError::BadRequest(
+                "Username or password is incorrect".to_string(),
+            ))
+        }
+    };
+    if !user.enabled {
+        return Err(auth::account_disabled());
+    }
+
+    let code: String = payload
+        .recovery_code
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+    let valid = user
+        .totp_recover
+        .as_ref()
+        .is_some_and(|expected| constant_time_eq(expected.as_bytes(), code.as_bytes()));
+    if !valid {
+        return Err(AppError::BadRequest(
+            "Recovery code is incorrect. Try again.".to_string(),
+        ));
+    }
+    limiter.refund_login(&email, &ip).await?;
+
+    // The code is single use, and remembered devices shouldn't skip a provider set up later.
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    db.batch(vec![
+        query!(&db, "DELETE FROM twofactor WHERE user_id = ?1", user.id)
+            .map_err(|_| AppError::Database)?,
+        query!(
+            &db,
+            "UPDATE users SET totp_recover = NULL, updated_at = ?1 WHERE id = ?2",
+            now,
+            user.id
+        )
+        .map_err(|_| AppError::Database)?,
+        query!(
+            &db,
+            "UPDATE devices SET twofactor_remember = NULL WHERE user_id = ?1",
+            user.id
+        )
+        .map_err(|_| AppError::Database)?,
+    ])
+    .await?;
+
+    Ok(Json(()))
+}
@@ -0,0 +1,51 @@
+//! "Remember this device" tokens.
+//!
+//! After a successful two-step login the client may ask to skip the second factor on that
+//! device. It gets a random token back, of which only a hash is kept on the device row. The
+//! hash covers the account's security stamp as well, so rotating the stamp forgets every device.
+
+use base64::{engine::general_purpose, Engine as _};
+use constant_time_eq::constant_time_eq;
+use ring::digest;
+use worker::{query, D1Database};
+
+use crate::{error::AppError, models::device::Device};
+
+fn hash_token(token: &str, security_stamp: &str) -> String {
+    let digest = digest::digest(
+        &digest::SHA256,
+        format!("{security_stamp}:{token}").as_bytes(),
+    );
+    general_purpose::STANDARD.encode(digest.as_ref())
+}
+
+/// Issues a new token for the device, replacing any earlier one.
+pub async fn remember_device(
+    db: &D1Database,
+    device: &Device,
+    security_stamp: &str,
+) -> Result<String, AppError> {
+    let token = general_purpose::URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+    query!(
+        db,
+        "UPDATE devices SET twofactor_remember = ?1 WHERE id = ?2",
+        hash_token(&token, security_stamp),
+        device.id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok(token)
+}
+
+pub fn check_token(device: Option<&Device>, security_stamp: &str, token: &str) -> bool {
+    device
+        .and_then(|device| device.twofactor_remember.as_ref())
+        .is_some_and(|stored| {
+            constant_time_eq(
+                stored.as_bytes(),
+                hash_token(token, security_stamp).as_bytes(),
+            )
+        })
+}
@@ -0,0 +1,324 @@
+use axum::{extract::State, Json};
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use worker::{query, D1Database, Env};
+
+use super::{check_password, delete_provider, get_two_factor, recover, save_two_factor};
+use crate::{
+    auth::Claims,
+    crypto::{base64url_decode, base64url_encode},
+    db,
+    error::AppError,
+    handlers::config,
+    models::two_factor::{
+        DeleteWebauthnData, EnableWebauthnData, PasswordData, TwoFactor, TwoFactorType,
+        WebauthnAssertion, WebauthnChallenge, WebauthnKeyResponse, WebauthnRegistration,
+        WebauthnResponse,
+    },
+    webauthn,
+};
+
+// How long a client has to answer a registration or login challenge.
+const CHALLENGE_TTL_MINUTES: i64 = 5;
+const CEREMONY_TIMEOUT_MS: i64 = 60_000;
+// Bitwarden clients offer five key slots.
+const MAX_KEYS: usize = 5;
+
+fn parse_registrations(
+    two_factor: Option<&TwoFactor>,
+) -> Result<Vec<WebauthnRegistration>, AppError> {
+    match two_factor {
+        Some(tf) => serde_json::from_str(&tf.data).map_err(|_| AppError::Internal),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn webauthn_response(registrations: &[WebauthnRegistration]) -> WebauthnResponse {
+    WebauthnResponse {
+        enabled: !registrations.is_empty(),
+        keys: registrations
+            .iter()
+            .map(|reg| WebauthnKeyResponse {
+                name: reg.name.clone(),
+                id: reg.id,
+                migrated: false,
+            })
+            .collect(),
+        object: "twoFactorWebAuthn".to_string(),
+    }
+}
+
+async fn new_challenge(
+    db: &D1Database,
+    user_id: &str,
+    r#type: TwoFactorType,
+) -> Result<String, AppError> {
+    let challenge = base64url_encode(&rand::random::<[u8; 32]>());
+    let data = serde_json::to_string(&WebauthnChallenge {
+        challenge: challenge.clone(),
+    })
+    .map_err(|_| AppError::Internal)?;
+    save_two_factor(db, user_id, r#type, &data, 0).await?;
+    Ok(challenge)
+}
+
+/// Consumes the pending challenge of the given type, so each one can only be answered once.
+///
+/// Reading and deleting is one statement: of two requests answering the same challenge, only
+/// the one that deleted the row gets it back.
+async fn take_challenge(
+    db: &D1Database,
+    user_id: &str,
+    r#type: TwoFactorType,
+) -> Result<Vec<u8>, AppError> {
+    let row: TwoFactor = query!(
+        db,
+        "DELETE FROM twofactor WHERE user_id = ?1 AND type = ?2 RETURNING *",
+        user_id,
+        r#type as i32
+    )
+    .map_err(|_| AppError::Database)?
+    .first(None)
+    .await
+    .map_err(|_| AppError::Database)?
+    .ok_or_else(|| AppError::BadRequest("No WebAuthn challenge pending".to_string()))?;
+
+    let issued = DateTime::parse_from_rfc3339(&row.updated_at).map_err(|_| AppError::Internal)?;
+    if Utc::now() - issued.with_timezone(&Utc) > Duration::minutes(CHALLENGE_TTL_MINUTES) {
+        return Err(AppError::BadRequest(
+            "WebAuthn challenge expired".to_string(),
+        ));
+    }
+
+    let challenge: WebauthnChallenge =
+        serde_json::from_str(&row.data).map_err(|_| AppError::Internal)?;
+    base64url_decode(&challenge.challenge)
+}
+
+#[worker::send]
+pub async fn get_webauthn(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<PasswordData>,
+) -> Result<Json<WebauthnResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    let two_factor = get_two_factor(&db, &user.id, TwoFactorType::Webauthn).await?;
+    let registrations = parse_registrations(two_factor.as_ref())?;
+
+    Ok(Json(webauthn_response(&registrations)))
+}
+
+#[worker::send]
+pub async fn generate_webauthn_challenge(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<PasswordData>,
+) -> Result<Json<Value>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    let two_factor = get_two_factor(&db, &user.id, TwoFactorType::Webauthn).await?;
+    let exclude_credentials: Vec<Value> = parse_registrations(two_factor.as_ref())?
+        .iter()
+        .map(|reg| json!({ "type": "public-key", "id": reg.credential_id }))
+        .collect();
+
+    let challenge = new_challenge(&db, &user.id, TwoFactorType::WebauthnRegisterChallenge).await?;
+
+    // PublicKeyCredentialCreationOptions, plus the status fields the clients check.
+    Ok(Json(json!({
+        "rp": {
+            "id": config::rp_id(&env),
+            "name": "Warden",
+        },
+        "user": {
+            "id": base64url_encode(user.id.as_bytes()),
+            "name": user.email,
+            "displayName": user.name.unwrap_or_else(|| user.email.clone()),
+        },
+        "challenge": challenge,
+        "pubKeyCredParams": [
+            { "type": "public-key", "alg": webauthn::COSE_ALG_ES256 },
+            { "type": "public-key", "alg": webauthn::COSE_ALG_EDDSA },
+            { "type": "public-key", "alg": webauthn::COSE_ALG_RS256 },
+        ],
+        "timeout": CEREMONY_TIMEOUT_MS,
+        "excludeCredentials": exclude_credentials,
+        "authenticatorSelection": {
+            "requireResidentKey": false,
+            "userVerification": "discouraged",
+        },
+        "attestation": "none",
+        "extensions": {},
+        "status": "ok",
+        "errorMessage": "",
+    })))
+}
+
+#[worker::send]
+pub async fn activate_webauthn(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<EnableWebauthnData>,
+) -> Result<Json<WebauthnResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    let challenge = take_challenge(&db, &user.id, TwoFactorType::WebauthnRegisterChallenge).await?;
+
+    let attestation = payload.device_response;
+    if attestation.r#type != "public-key" {
+        return Err(AppError::BadRequest("Invalid credential type".to_string()));
+    }
+    let credential = webauthn::verify_registration(
+        &config::rp_id(&env),
+        &config::domain(&env),
+        &challenge,
+        &base64url_decode(&attestation.response.client_data_json)?,
+        &base64url_decode(&attestation.response.attestation_object)?,
+    )?;
+    if credential.credential_id != base64url_decode(&attestation.raw_id)? {
+        return Err(AppError::BadRequest("Credential id mismatch".to_string()));
+    }
+
+    let two_factor = get_two_factor(&db, &user.id, TwoFactorType::Webauthn).await?;
+    let mut registrations = parse_registrations(two_factor.as_ref())?;
+
+    let credential_id = base64url_encode(&credential.credential_id);
+    if registrations
+        .iter()
+        .any(|reg| reg.credential_id == credential_id)
+    {
+        return Err(AppError::BadRequest(
+            "This security key is already registered".to_string(),
+        ));
+    }
+
+    // Registering into an occupied slot replaces the key in it.
+    registrations.retain(|reg| reg.id != payload.id);
+    if registrations.len() >= MAX_KEYS {
+        return Err(AppError::BadRequest(
+            "Too many security keys registered".to_string(),
+        ));
+    }
+    registrations.push(WebauthnRegistration {
+        id: payload.id,
+        name: payload.name,
+        credential_id,
+        public_key: base64url_encode(&credential.public_key),
+        sign_count: credential.sign_count,
+    });
+    registrations.sort_by_key(|reg| reg.id);
+
+    let data = serde_json::to_string(&registrations).map_err(|_| AppError::Internal)?;
+    save_two_factor(&db, &user.id, TwoFactorType::Webauthn, &data, 0).await?;
+    recover::ensure_recovery_code(&db, &user).await?;
+
+    Ok(Json(webauthn_response(&registrations)))
+}
+
+#[worker::send]
+pub async fn delete_webauthn(
+    claims: Claims,
+    State(env): State<Arc<Env>>,
+    Json(payload): Json<DeleteWebauthnData>,
+) -> Result<Json<WebauthnResponse>, AppError> {
+    let db = db::get_db(&env)?;
+    let user = db::get_user(&db, &claims.sub).await?;
+    check_password(&user, &payload.master_password_hash).await?;
+
+    let two_factor = get_two_factor(&db, &user.id, TwoFactorType::Webauthn)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Security key not found".to_string()))?;
+    let mut registrations = parse_registrations(Some(&two_factor))?;
+
+    let count = registrations.len();
+    registrations.retain(|reg| reg.id != payload.id);
+    if registrations.len() == count {
+        return Err(AppError::NotFound("Security key not found".to_string()));
+    }
+
+    if registrations.is_empty() {
+        delete_provider(&db, &user.id, TwoFactorType::Webauthn).await?;
+    } else {
+        let data = serde_json::to_string(&registrations).map_err(|_| AppError::Internal)?;
+        save_two_factor(&db, &user.id, TwoFactorType::Webauthn, &data, 0).await?;
+    }
+
+    Ok(Json(webauthn_response(&registrations)))
+}
+
+/// Creates the PublicKeyCredentialRequestOptions sent in the login challenge.
+pub async fn login_challenge(
+    env: &Env,
+    db: &D1Database,
+    two_factor: &TwoFactor,
+) -> Result<Value, AppError> {
+    let allow_credentials: Vec<Value> = parse_registrations(Some(two_factor))?
+        .iter()
+        .map(|reg| json!({ "type": "public-key", "id": reg.credential_id }))
+        .collect();
+
+    let challenge = new_challenge(
+        db,
+        &two_factor.user_id,
+        TwoFactorType::WebauthnLoginChallenge,
+    )
+    .await?;
+
+    Ok(json!({
+        "challenge": challenge,
+        "timeout": CEREMONY_TIMEOUT_MS,
+        "rpId": config::rp_id(env),
+        "allowCredentials": allow_credentials,
+        "userVerification": "discouraged",
+        "extensions": {},
+    }))
+}
+
+/// Validates the assertion sent as `twoFactorToken` and stores the new signature counter.
+pub async fn validate_login_assertion(
+    env: &Env,
+    db: &D1Database,
+    two_factor: &TwoFactor,
+    token: &str,
+) -> Result<(), AppError> {
+    let invalid = || AppError::BadRequest("Two-step token is invalid. Try again.".to_string());
+
+    let assertion: WebauthnAssertion = serde_json::from_str(token).map_err(|_| invalid())?;
+    let challenge = take_challenge(
+        db,
+        &two_factor.user_id,
+        TwoFactorType::WebauthnLoginChallenge,
+    )
+    .await?;
+
+    let mut registrations = parse_registrations(Some(two_factor))?;
+    let credential_id = base64url_encode(&base64url_decode(&assertion.raw_id)?);
+    let registration = registrations
+        .iter_mut()
+        .find(|reg| reg.credential_id == credential_id)
+        .ok_or_else(invalid)?;
+
+    registration.sign_count = webauthn::verify_assertion(
+        &config::rp_id(env),
+        &config::domain(env),
+        &challenge,
+        &base64url_decode(&registration.public_key)?,
+        registration.sign_count,
+        &base64url_decode(&assertion.response.client_data_json)?,
+        &base64url_decode(&assertion.response.authenticator_data)?,
+        &base64url_decode(&assertion.response.signature)?,
+    )?;
+
+    let data = serde_json::to_string(&registrations).map_err(|_| AppError::Internal)?;
+    save_two_factor(db, &two_factor.user_id, TwoFactorType::Webauthn, &data, 0).await?;
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+//! Signing and verifying the JWTs the server issues.
+//!
+//! Tokens are signed with Ed25519 keys from the `JWT_SIGNING_KEYS` secret, a comma or newline
+//! separated list of `<kid>:<base64 PKCS#8 DER>` entries, for example made with
+//! `openssl genpkey -algorithm ed25519 -outform DER | base64`. The last entry signs, every entry
+//! verifies, so a key is rotated by appending a new one and dropping the old one once the tokens
+//! it signed have expired. The public keys are published at `/identity/.well-known/jwks`.
+//!
+//! Without `JWT_SIGNING_KEYS` tokens are signed with HS256 and `JWT_SECRET`, as they used to be.
+//! HS256 tokens are accepted as long as `JWT_SECRET` is set, so it can be removed once every
+//! such token has expired.
+
+use base64::{engine::general_purpose, Engine as _};
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use worker::Env;
+
+use crate::{crypto::base64url_encode, error::AppError};
+
+struct SigningKey {
+    kid: String,
+    encoding: EncodingKey,
+    public_key: Vec<u8>,
+}
+
+/// The configured signing keys, oldest first.
+fn signing_keys(env: &Env) -> Result<Vec<SigningKey>, AppError> {
+    let Ok(secret) = env.secret("JWT_SIGNING_KEYS") else {
+        return Ok(Vec::new());
+    };
+
+    secret
+        .to_string()
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let invalid = || {
+                log::error!("Invalid entry in JWT_SIGNING_KEYS");
+                AppError::Internal
+            };
+            let (kid, der) = entry.split_once(':').ok_or_else(invalid)?;
+            let der = general_purpose::STANDARD
+                .decode(der)
+                .map_err(|_| invalid())?;
+            let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der).map_err(|_| invalid())?;
+            Ok(SigningKey {
+                kid: kid.to_string(),
+                public_key: pair.public_key().as_ref().to_vec(),
+                encoding: EncodingKey::from_ed_der(&der),
+            })
+        })
+        .collect()
+}
+
+fn legacy_secret(env: &Env) -> Option<String> {
+    env.secret("JWT_SECRET")
+        .ok()
+        .map(|secret| secret.to_string())
+}
+
+/// Signs the claims with the newest signing key.
+pub fn sign<T: Serialize>(env: &Env, claims: &T) -> Result<String, AppError> {
+    if let Some(key) = signing_keys(env)?.pop() {
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(key.kid);
+        return Ok(encode(&header, claims, &key.encoding)?);
+    }
+
+    let secret = legacy_secret(env).ok_or(AppError::Internal)?;
+    Ok(encode(
+        &Header::default(),
+        claims,
+        &EncodingKey::from_secret(secret.as_ref()),
+    )?)
+}
+
+/// Checks the signature and lifetime of a token signed by [`sign`] and returns its claims.
+pub fn verify<T: DeserializeOwned>(env: &Env, token: &str) -> Result<T, AppError> {
+    let header = decode_header(token)?;
+    let (key, algorithm) = match (header.alg, header.kid) {
+        (Algorithm::EdDSA, Some(kid)) => {
+            let key = signing_keys(env)?
+                .into_iter()
+                .find(|key| key.kid == kid)
+                .ok_or_else(|| AppError::Unauthorized("Invalid token".to_string()))?;
+            (DecodingKey::from_ed_der(&key.public_key), Algorithm::EdDSA)
+        }
+        (Algorithm::HS256, None) => {
+            let secret = legacy_secret(env)
+                .ok_or_else(|| AppError::Unauthorized("Invalid token".to_string()))?;
+            (DecodingKey::from_secret(secret.as_ref()), Algorithm::HS256)
+        }
+        _ => return Err(AppError::Unauthorized("Invalid token".to_string())),
+    };
+
+    Ok(decode::<T>(token, &key, &Validation::new(algorithm))?.claims)
+}
+
+/// The public signing keys as a JSON Web Key Set.
+pub fn jwks(env: &Env) -> Result<Value, AppError> {
+    let keys: Vec<Value> = signing_keys(env)?
+        .into_iter()
+        .map(|key| {
+            json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "use": "sig",
+                "alg": "EdDSA",
+                "kid": key.kid,
+                "x": base64url_encode(&key.public_key),
+            })
+        })
+        .collect();
+
+    Ok(json!({ "keys": keys }))
+}
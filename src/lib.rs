@@ -7,8 +7,15 @@ mod crypto;
 mod db;
 mod error;
 mod handlers;
+mod jwt;
+mod mail;
 mod models;
+mod oidc;
+mod rate_limit;
+mod refresh_token;
 mod router;
+mod signup;
+mod webauthn;
 
 #[event(fetch)]
 pub async fn main(
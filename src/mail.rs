@@ -0,0 +1,120 @@
+//! Outgoing mail.
+//!
+//! The transport is picked with the `MAIL_TRANSPORT` variable:
+//! - `mailchannels` posts to the MailChannels Email API, authenticated with the
+//!   `MAILCHANNELS_API_KEY` secret and sent from `MAIL_FROM`.
+//!
+//! Without `MAIL_TRANSPORT` mail is disabled and features that need it are turned off.
+//!
+//! Tests use [`Mailer::Memory`], which keeps the messages instead of sending them.
+
+use serde_json::json;
+#[cfg(test)]
+use std::cell::RefCell;
+use wasm_bindgen::JsValue;
+use worker::{Env, Fetch, Headers, Method, Request, RequestInit};
+
+use crate::error::AppError;
+
+const MAILCHANNELS_SEND_URL: &str = "https://api.mailchannels.net/tx/v1/send";
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub enum Mailer {
+    MailChannels {
+        api_key: String,
+        from: String,
+        from_name: String,
+    },
+    #[cfg(test)]
+    Memory(RefCell<Vec<Email>>),
+}
+
+impl Mailer {
+    /// Returns the configured transport, or `None` when mail is disabled.
+    pub fn from_env(env: &Env) -> Result<Option<Self>, AppError> {
+        let transport = match env.var("MAIL_TRANSPORT") {
+            Ok(transport) => transport.to_string(),
+            Err(_) => return Ok(None),
+        };
+
+        match transport.as_str() {
+            "mailchannels" => Ok(Some(Mailer::MailChannels {
+                api_key: env.secret("MAILCHANNELS_API_KEY")?.to_string(),
+                from: env.var("MAIL_FROM")?.to_string(),
+                from_name: env
+                    .var("MAIL_FROM_NAME")
+                    .map(|name| name.to_string())
+                    .unwrap_or_else(|_| "Warden".to_string()),
+            })),
+            other => {
+                log::error!("Unknown MAIL_TRANSPORT {other:?}");
+                Err(AppError::Internal)
+            }
+        }
+    }
+
+    /// Like [`Mailer::from_env`], for features that can't work without mail.
+    pub fn required(env: &Env) -> Result<Self, AppError> {
+        Self::from_env(env)?.ok_or_else(|| {
+            AppError::BadRequest("Email is not configured on this server".to_string())
+        })
+    }
+
+    pub async fn send(&self, email: &Email) -> Result<(), AppError> {
+        match self {
+            Mailer::MailChannels {
+                api_key,
+                from,
+                from_name,
+            } => send_mailchannels(api_key, from, from_name, email).await,
+            #[cfg(test)]
+            Mailer::Memory(outbox) => {
+                outbox.borrow_mut().push(email.clone());
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn send_mailchannels(
+    api_key: &str,
+    from: &str,
+    from_name: &str,
+    email: &Email,
+) -> Result<(), AppError> {
+    let body = json!({
+        "personalizations": [{ "to": [{ "email": email.to }] }],
+        "from": { "email": from, "name": from_name },
+        "subject": email.subject,
+        "content": [{ "type": "text/plain", "value": email.body }],
+    });
+
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set("X-Api-Key", api_key)?;
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(JsValue::from_str(&body.to_string())));
+
+    let request = Request::new_with_init(MAILCHANNELS_SEND_URL, &init)?;
+    let mut response = Fetch::Request(request).send().await?;
+
+    if !(200..300).contains(&response.status_code()) {
+        let error = response.text().await.unwrap_or_default();
+        log::error!(
+            "MailChannels rejected mail ({}): {error}",
+            response.status_code()
+        );
+        return Err(AppError::Internal);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+use super::device::DeviceType;
+
+pub const AUTH_REQUEST_LOGIN: i32 = 0;
+pub const AUTH_REQUEST_UNLOCK: i32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthRequest {
+    pub id: String,
+    pub user_id: String,
+    pub r#type: i32,
+    pub request_device_identifier: String,
+    pub request_device_type: i32,
+    pub request_ip: String,
+    pub public_key: String,
+    pub access_code_hash: String,
+    pub approved: Option<i32>,
+    pub response_device_identifier: Option<String>,
+    pub key: Option<String>,
+    pub master_password_hash: Option<String>,
+    pub response_date: Option<String>,
+    pub authentication_date: Option<String>,
+    pub expires_at: i64,
+    pub created_at: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthRequestResponse {
+    pub id: String,
+    pub public_key: String,
+    pub request_device_type: String,
+    pub request_device_type_value: i32,
+    pub request_device_identifier: String,
+    pub request_ip_address: String,
+    pub key: Option<String>,
+    pub master_password_hash: Option<String>,
+    pub creation_date: String,
+    pub response_date: Option<String>,
+    pub request_approved: Option<bool>,
+    pub origin: String,
+    pub object: String,
+}
+
+impl AuthRequestResponse {
+    pub fn new(request: AuthRequest, origin: String) -> Self {
+        AuthRequestResponse {
+            id: request.id,
+            public_key: request.public_key,
+            request_device_type: format!("{:?}", DeviceType::from(request.request_device_type)),
+            request_device_type_value: request.request_device_type,
+            request_device_identifier: request.request_device_identifier,
+            request_ip_address: request.request_ip,
+            key: request.key,
+            master_password_hash: request.master_password_hash,
+            creation_date: request.created_at,
+            response_date: request.response_date,
+            request_approved: request.approved.map(|approved| approved != 0),
+            origin,
+            object: "auth-request".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthRequestListResponse {
+    pub data: Vec<AuthRequestResponse>,
+    pub object: String,
+    pub continuation_token: Option<String>,
+}
+
+// For POST /auth-requests request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateAuthRequest {
+    pub email: String,
+    pub public_key: String,
+    pub device_identifier: String,
+    pub access_code: String,
+    pub r#type: i32,
+}
+
+// For PUT /auth-requests/{id} request. `key` and `master_password_hash` are encrypted to the
+// requesting device's public key by the approving client.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateAuthRequest {
+    pub key: Option<String>,
+    pub master_password_hash: Option<String>,
+    pub device_identifier: String,
+    pub request_approved: bool,
+}
+
+// For GET /auth-requests/{id}/response query
+#[derive(Debug, Deserialize)]
+pub struct AuthRequestCode {
+    pub code: String,
+}
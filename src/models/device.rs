@@ -11,11 +11,16 @@ pub struct Device {
     pub push_token: Option<String>,
     pub r#type: i32,
     pub name: Option<String>,
+    // Hash of the "remember this device" two-factor token, see `two_factor::remember`.
+    pub twofactor_remember: Option<String>,
+    pub last_active_at: Option<String>,
     pub created_at: String,
     pub updated_at: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// Numbering of the official clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum DeviceType {
     Android = 0,
     #[allow(non_camel_case_types)]
@@ -27,12 +32,116 @@ pub enum DeviceType {
     WindowsDesktop = 6,
     MacOsDesktop = 7,
     LinuxDesktop = 8,
-    Web = 9,
-    VivaldiExtension = 10,
-    SafariExtension = 11,
-    SDK = 12,
-    Server = 13,
-    WindowsStoreDesktop = 14,
-    BraveExtension = 15,
-    TorExtension = 16,
+    ChromeBrowser = 9,
+    FirefoxBrowser = 10,
+    OperaBrowser = 11,
+    EdgeBrowser = 12,
+    IEBrowser = 13,
+    UnknownBrowser = 14,
+    AndroidAmazon = 15,
+    UWP = 16,
+    SafariBrowser = 17,
+    VivaldiBrowser = 18,
+    VivaldiExtension = 19,
+    SafariExtension = 20,
+    SDK = 21,
+    Server = 22,
+    WindowsCLI = 23,
+    MacOsCLI = 24,
+    LinuxCLI = 25,
+    DuckDuckGoBrowser = 26,
+}
+
+impl DeviceType {
+    const ALL: [DeviceType; 27] = [
+        DeviceType::Android,
+        DeviceType::iOS,
+        DeviceType::ChromeExtension,
+        DeviceType::FirefoxExtension,
+        DeviceType::OperaExtension,
+        DeviceType::EdgeExtension,
+        DeviceType::WindowsDesktop,
+        DeviceType::MacOsDesktop,
+        DeviceType::LinuxDesktop,
+        DeviceType::ChromeBrowser,
+        DeviceType::FirefoxBrowser,
+        DeviceType::OperaBrowser,
+        DeviceType::EdgeBrowser,
+        DeviceType::IEBrowser,
+        DeviceType::UnknownBrowser,
+        DeviceType::AndroidAmazon,
+        DeviceType::UWP,
+        DeviceType::SafariBrowser,
+        DeviceType::VivaldiBrowser,
+        DeviceType::VivaldiExtension,
+        DeviceType::SafariExtension,
+        DeviceType::SDK,
+        DeviceType::Server,
+        DeviceType::WindowsCLI,
+        DeviceType::MacOsCLI,
+        DeviceType::LinuxCLI,
+        DeviceType::DuckDuckGoBrowser,
+    ];
+}
+
+// The type is whatever the client sent at login, so unknown numbers are kept as an unknown browser.
+impl From<i32> for DeviceType {
+    fn from(value: i32) -> Self {
+        DeviceType::ALL
+            .into_iter()
+            .find(|device_type| *device_type as i32 == value)
+            .unwrap_or(DeviceType::UnknownBrowser)
+    }
+}
+
+impl Serialize for DeviceType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i32(*self as i32)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceResponse {
+    pub id: String,
+    pub user_id: Option<String>,
+    pub name: Option<String>,
+    pub identifier: String,
+    pub r#type: DeviceType,
+    pub creation_date: String,
+    pub revision_date: String,
+    pub last_activity_date: Option<String>,
+    pub is_trusted: bool,
+    pub object: String,
+}
+
+impl From<Device> for DeviceResponse {
+    fn from(device: Device) -> Self {
+        DeviceResponse {
+            id: device.id,
+            user_id: device.user_id,
+            name: device.name,
+            identifier: device.identifier,
+            r#type: device.r#type.into(),
+            creation_date: device.created_at,
+            revision_date: device.updated_at,
+            last_activity_date: device.last_active_at,
+            is_trusted: false,
+            object: "device".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceListResponse {
+    pub data: Vec<DeviceResponse>,
+    pub object: String,
+    pub continuation_token: Option<String>,
+}
+
+// For PUT /devices/{id} request. The clients send the whole device, only the name is changed.
+#[derive(Debug, Deserialize)]
+pub struct UpdateDeviceRequest {
+    pub name: String,
 }
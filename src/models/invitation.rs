@@ -0,0 +1,11 @@
+use serde::{Deserialize, Serialize};
+
+/// A signup invitation the admin issued for one address.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Invitation {
+    pub id: String,
+    pub email: String,
+    pub expires_at: i64,
+    pub used_at: Option<String>,
+    pub created_at: String,
+}
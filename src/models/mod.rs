@@ -5,3 +5,6 @@ pub mod sync;
 pub mod cipher;
 pub mod folder;
 pub mod import;
+pub mod two_factor;
+pub mod auth_request;
+pub mod invitation;
@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+
+// Bitwarden's TwoFactorProviderType values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TwoFactorType {
+    Authenticator = 0,
+    Email = 1,
+    // Not stored: clients send it with the token from an earlier "remember this device".
+    Remember = 5,
+    Webauthn = 7,
+
+    // Pending setups and ceremonies, stored alongside the providers but never offered as one.
+    EmailVerificationChallenge = 1002,
+    WebauthnRegisterChallenge = 1003,
+    WebauthnLoginChallenge = 1004,
+}
+
+impl TwoFactorType {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(TwoFactorType::Authenticator),
+            1 => Some(TwoFactorType::Email),
+            5 => Some(TwoFactorType::Remember),
+            7 => Some(TwoFactorType::Webauthn),
+            1002 => Some(TwoFactorType::EmailVerificationChallenge),
+            1003 => Some(TwoFactorType::WebauthnRegisterChallenge),
+            1004 => Some(TwoFactorType::WebauthnLoginChallenge),
+            _ => None,
+        }
+    }
+
+    /// Whether this type is a provider the user can log in with.
+    pub fn is_provider(self) -> bool {
+        matches!(
+            self,
+            TwoFactorType::Authenticator | TwoFactorType::Email | TwoFactorType::Webauthn
+        )
+    }
+}
+
+// A row of the `twofactor` table.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TwoFactor {
+    pub id: String,
+    pub user_id: String,
+    pub r#type: i32,
+    pub data: String,
+    pub last_used: i64,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+// Body of the requests that require the user to re-enter the master password.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordData {
+    pub master_password_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnableAuthenticatorData {
+    pub key: String,
+    pub token: String,
+    pub master_password_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DisableTwoFactorData {
+    pub r#type: i32,
+    pub master_password_hash: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorResponse {
+    pub enabled: bool,
+    pub key: String,
+    pub object: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TwoFactorProviderResponse {
+    pub enabled: bool,
+    pub r#type: i32,
+    pub object: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TwoFactorListResponse {
+    pub data: Vec<TwoFactorProviderResponse>,
+    pub object: String,
+    pub continuation_token: Option<String>,
+}
+
+// A security key stored in the `data` of the WebAuthn provider row.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebauthnRegistration {
+    pub id: i32,
+    pub name: String,
+    pub credential_id: String,
+    pub public_key: String,
+    pub sign_count: u32,
+}
+
+// A pending registration or login challenge.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnChallenge {
+    pub challenge: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnAttestationResponse {
+    pub attestation_object: String,
+    #[serde(alias = "clientDataJSON")]
+    pub client_data_json: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnAttestation {
+    pub raw_id: String,
+    pub r#type: String,
+    pub response: WebauthnAttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnableWebauthnData {
+    pub id: i32,
+    pub name: String,
+    pub device_response: WebauthnAttestation,
+    pub master_password_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteWebauthnData {
+    pub id: i32,
+    pub master_password_hash: String,
+}
+
+// The `twoFactorToken` of a WebAuthn login, which is the JSON encoded assertion.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnAssertionResponse {
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(alias = "clientDataJSON")]
+    pub client_data_json: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnAssertion {
+    pub raw_id: String,
+    pub response: WebauthnAssertionResponse,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnKeyResponse {
+    pub name: String,
+    pub id: i32,
+    pub migrated: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnResponse {
+    pub enabled: bool,
+    pub keys: Vec<WebauthnKeyResponse>,
+    pub object: String,
+}
+
+// The `data` of the email provider row, and of its pending setup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailTokenData {
+    pub email: String,
+    pub last_token: Option<String>,
+    pub token_sent: i64,
+    pub attempts: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendEmailData {
+    pub email: String,
+    pub master_password_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnableEmailData {
+    pub email: String,
+    pub token: String,
+    pub master_password_hash: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailResponse {
+    pub email: Option<String>,
+    pub enabled: bool,
+    pub object: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoverResponse {
+    pub code: String,
+    pub object: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoverTwoFactorData {
+    pub email: String,
+    pub master_password_hash: String,
+    pub recovery_code: String,
+}
@@ -1,5 +1,12 @@
+use constant_time_eq::constant_time_eq;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    crypto::{self, PasswordHash},
+    error::AppError,
+    models::cipher::CipherRequestData,
+};
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub id: String,
@@ -7,19 +14,58 @@ pub struct User {
     pub email: String,
     #[serde(with = "bool_from_int")]
     pub email_verified: bool,
+    #[serde(with = "bool_from_int")]
+    pub enabled: bool,
+    pub email_new: Option<String>,
+    pub email_new_token: Option<String>,
+    pub email_new_token_sent: i64,
     pub master_password_hash: String,
+    // Salt and iterations of the server side hash of `master_password_hash`. Accounts created
+    // before it existed have no salt and hold the client hash as is until their next login.
+    pub password_salt: Option<String>,
+    pub password_iterations: i32,
     pub master_password_hint: Option<String>,
+    pub avatar_color: Option<String>,
     pub key: String,
     pub private_key: String,
     pub public_key: String,
     pub kdf_type: i32,
     pub kdf_iterations: i32,
+    pub kdf_memory: Option<i32>,
+    pub kdf_parallelism: Option<i32>,
     pub security_stamp: String,
+    pub totp_recover: Option<String>,
+    pub api_key: Option<String>,
     pub created_at: String,
     pub updated_at: String,
 }
 
-mod bool_from_int {
+impl User {
+    /// Securely compares a client supplied master password hash with the stored one.
+    pub async fn check_master_password(&self, password_hash: &str) -> Result<bool, AppError> {
+        let Some(salt) = &self.password_salt else {
+            return Ok(constant_time_eq(
+                self.master_password_hash.as_bytes(),
+                password_hash.as_bytes(),
+            ));
+        };
+
+        let stored = PasswordHash {
+            hash: self.master_password_hash.clone(),
+            salt: salt.clone(),
+            iterations: self.password_iterations as u32,
+        };
+        crypto::verify_password(password_hash, &stored).await
+    }
+
+    /// Whether the stored hash is missing or weaker than what [`crypto::hash_password`] makes.
+    pub fn needs_password_rehash(&self) -> bool {
+        self.password_salt.is_none()
+            || self.password_iterations < crypto::PASSWORD_ITERATIONS as i32
+    }
+}
+
+pub(crate) mod bool_from_int {
     use serde::{self, Deserialize, Deserializer, Serializer};
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<bool, D::Error>
@@ -46,12 +92,76 @@ mod bool_from_int {
     }
 }
 
+/// Tells a field that was sent as `null`, `Some(None)`, from one that was left out, `None`, for
+/// requests that only change the fields they carry. Use with `#[serde(default)]`.
+pub(crate) mod present {
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        Option::<T>::deserialize(deserializer).map(Some)
+    }
+}
+
+pub const KDF_PBKDF2: i32 = 0;
+pub const KDF_ARGON2ID: i32 = 1;
+
+/// The client side key derivation settings of an account.
+#[derive(Debug, Clone, Copy)]
+pub struct KdfParams {
+    pub kdf_type: i32,
+    pub iterations: i32,
+    pub memory: Option<i32>,      // MiB, Argon2id only
+    pub parallelism: Option<i32>, // Argon2id only
+}
+
+impl KdfParams {
+    /// Rejects unknown KDFs and settings too weak to protect the vault. Memory and parallelism
+    /// are dropped for PBKDF2, which has no use for them.
+    pub fn validate(self) -> Result<Self, AppError> {
+        let invalid = |msg: &str| Err(AppError::BadRequest(msg.to_string()));
+        match self.kdf_type {
+            KDF_PBKDF2 => {
+                if self.iterations < 100_000 {
+                    return invalid("PBKDF2 KDF iterations must be at least 100000.");
+                }
+                Ok(KdfParams {
+                    memory: None,
+                    parallelism: None,
+                    ..self
+                })
+            }
+            KDF_ARGON2ID => {
+                if self.iterations < 1 {
+                    return invalid("Argon2 KDF iterations must be at least 1.");
+                }
+                if !self.memory.is_some_and(|memory| (15..=1024).contains(&memory)) {
+                    return invalid("Argon2 memory must be between 15 MB and 1024 MB.");
+                }
+                if !self
+                    .parallelism
+                    .is_some_and(|parallelism| (1..=16).contains(&parallelism))
+                {
+                    return invalid("Argon2 parallelism must be between 1 and 16.");
+                }
+                Ok(self)
+            }
+            _ => invalid("Unsupported KDF type."),
+        }
+    }
+}
+
 // For /accounts/prelogin response
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PreloginResponse {
     pub kdf: i32,
     pub kdf_iterations: i32,
+    pub kdf_memory: Option<i32>,
+    pub kdf_parallelism: Option<i32>,
 }
 
 // For /accounts/register request
@@ -66,6 +176,12 @@ pub struct RegisterRequest {
     pub user_asymmetric_keys: KeyData,
     pub kdf: i32,
     pub kdf_iterations: i32,
+    pub kdf_memory: Option<i32>,
+    pub kdf_parallelism: Option<i32>,
+    // The token from send-verification-email or an invitation token, sent under either name
+    // depending on the client
+    pub org_invite_token: Option<String>,
+    pub email_verification_token: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -74,3 +190,155 @@ pub struct KeyData {
     pub public_key: String,
     pub encrypted_private_key: String,
 }
+
+// For /accounts/password request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePasswordRequest {
+    pub master_password_hash: String,
+    pub new_master_password_hash: String,
+    pub master_password_hint: Option<String>,
+    pub key: String, // The user key, encrypted with the new master key
+}
+
+// For /accounts/kdf request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeKdfRequest {
+    pub kdf: i32,
+    pub kdf_iterations: i32,
+    pub kdf_memory: Option<i32>,
+    pub kdf_parallelism: Option<i32>,
+    pub master_password_hash: String,
+    pub new_master_password_hash: String,
+    pub key: String, // The user key, encrypted with the master key derived with the new KDF
+}
+
+// For /accounts/key request. Everything encrypted with the user key, re-encrypted with a new one.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyRotationRequest {
+    pub master_password_hash: String,
+    pub key: String,
+    pub private_key: String,
+    pub ciphers: Vec<RotatedCipher>,
+    pub folders: Vec<RotatedFolder>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RotatedCipher {
+    pub id: String,
+    #[serde(flatten)]
+    pub cipher: CipherRequestData,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RotatedFolder {
+    pub id: String,
+    pub name: String,
+}
+
+// For /accounts/email-token request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailTokenRequest {
+    pub new_email: String,
+    pub master_password_hash: String,
+}
+
+// For /accounts/email request. The email is the KDF salt, so the master password hash and
+// the wrapped user key change with it.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeEmailRequest {
+    pub new_email: String,
+    pub master_password_hash: String,
+    pub new_master_password_hash: String,
+    pub token: String,
+    pub key: String,
+}
+
+// For /accounts/register/send-verification-email request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendVerificationEmailRequest {
+    pub email: String,
+    pub name: Option<String>,
+}
+
+// For /accounts/register/verification-email-clicked request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerificationEmailClickedRequest {
+    pub email: String,
+    pub email_verification_token: String,
+}
+
+// For /accounts/verify-email-token request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyEmailTokenRequest {
+    pub user_id: String,
+    pub token: String,
+}
+
+// For /accounts/delete-recover request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteRecoverRequest {
+    pub email: String,
+}
+
+// For /accounts/delete-recover-token request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteRecoverTokenRequest {
+    pub user_id: String,
+    pub token: String,
+}
+
+// For /accounts/api-key and /accounts/rotate-api-key response
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyResponse {
+    pub api_key: String,
+    pub revision_date: String,
+    pub object: String,
+}
+
+// For /accounts/profile request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateProfileRequest {
+    // Kept when missing, cleared when null.
+    #[serde(default, deserialize_with = "present::deserialize")]
+    pub name: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present::deserialize")]
+    pub master_password_hint: Option<Option<String>>,
+    // Kept when missing. The clients set it through /accounts/avatar, which can also clear it.
+    pub avatar_color: Option<String>,
+}
+
+// For /accounts/avatar request
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateAvatarRequest {
+    pub avatar_color: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_update_tells_missing_from_null() {
+        let update: UpdateProfileRequest =
+            serde_json::from_str(r#"{"name": "Alice", "masterPasswordHint": null}"#).unwrap();
+        assert_eq!(update.name, Some(Some("Alice".to_string())));
+        assert_eq!(update.master_password_hint, Some(None));
+
+        let update: UpdateProfileRequest = serde_json::from_str("{}").unwrap();
+        assert_eq!(update.name, None);
+        assert_eq!(update.master_password_hint, None);
+    }
+}
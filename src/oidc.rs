@@ -0,0 +1,240 @@
+//! OpenID Connect relying party, for single sign-on through the company identity provider.
+//!
+//! Only what the authorization code flow needs: provider discovery, the authorization URL, the
+//! code exchange and ID token verification. The provider is configured with the `SSO_AUTHORITY`
+//! (issuer URL) and `SSO_CLIENT_ID` variables, the optional `SSO_CLIENT_SECRET` secret and the
+//! optional `SSO_SCOPES` variable.
+
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use wasm_bindgen::JsValue;
+use worker::{Env, Fetch, Headers, Method, Request, RequestInit};
+
+use crate::error::AppError;
+
+const DEFAULT_SCOPES: &str = "openid email profile";
+
+pub struct OidcConfig {
+    pub authority: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: String,
+}
+
+/// The provider settings, or `None` when SSO isn't set up.
+pub fn config(env: &Env) -> Option<OidcConfig> {
+    let var = |name| {
+        env.var(name)
+            .ok()
+            .map(|value| value.to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    Some(OidcConfig {
+        authority: var("SSO_AUTHORITY")?.trim_end_matches('/').to_string(),
+        client_id: var("SSO_CLIENT_ID")?,
+        client_secret: env
+            .secret("SSO_CLIENT_SECRET")
+            .ok()
+            .map(|secret| secret.to_string()),
+        scopes: var("SSO_SCOPES").unwrap_or_else(|| DEFAULT_SCOPES.to_string()),
+    })
+}
+
+/// The parts of the provider's `.well-known/openid-configuration` we use.
+#[derive(Debug, Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub nonce: Option<String>,
+}
+
+fn provider_error(what: &str) -> AppError {
+    log::error!("SSO provider error: {what}");
+    AppError::BadRequest("The SSO provider could not be reached".to_string())
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(url: &str) -> Result<T, AppError> {
+    let mut response = Fetch::Url(url.parse().map_err(|_| provider_error(url))?)
+        .send()
+        .await?;
+    if !(200..300).contains(&response.status_code()) {
+        return Err(provider_error(&format!(
+            "{url} answered {}",
+            response.status_code()
+        )));
+    }
+    response
+        .json()
+        .await
+        .map_err(|_| provider_error(&format!("{url} sent invalid JSON")))
+}
+
+pub fn discovery_url(config: &OidcConfig) -> String {
+    format!("{}/.well-known/openid-configuration", config.authority)
+}
+
+pub async fn discover(config: &OidcConfig) -> Result<Discovery, AppError> {
+    get_json(&discovery_url(config)).await
+}
+
+/// Where to send the user to log in at the provider.
+pub fn authorization_url(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    redirect_uri: &str,
+    state: &str,
+    nonce: &str,
+    code_challenge: &str,
+) -> Result<String, AppError> {
+    let query = serde_urlencoded::to_string([
+        ("response_type", "code"),
+        ("client_id", config.client_id.as_str()),
+        ("redirect_uri", redirect_uri),
+        ("scope", config.scopes.as_str()),
+        ("state", state),
+        ("nonce", nonce),
+        ("code_challenge", code_challenge),
+        ("code_challenge_method", "S256"),
+    ])
+    .map_err(|_| AppError::Internal)?;
+    let separator = if discovery.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+
+    Ok(format!(
+        "{}{separator}{query}",
+        discovery.authorization_endpoint
+    ))
+}
+
+/// The form [`exchange_code`] posts to the token endpoint.
+pub fn token_request_body(
+    config: &OidcConfig,
+    code: &str,
+    redirect_uri: &str,
+    code_verifier: &str,
+) -> Result<String, AppError> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri),
+        ("client_id", config.client_id.as_str()),
+        ("code_verifier", code_verifier),
+    ];
+    if let Some(secret) = &config.client_secret {
+        form.push(("client_secret", secret.as_str()));
+    }
+    serde_urlencoded::to_string(form).map_err(|_| AppError::Internal)
+}
+
+/// Reads the ID token out of the token endpoint's answer.
+pub fn id_token_from_response(body: &str) -> Result<String, AppError> {
+    let response: TokenResponse = serde_json::from_str(body)
+        .map_err(|_| provider_error("token endpoint sent no ID token"))?;
+    Ok(response.id_token)
+}
+
+/// Trades the provider's authorization code for an ID token.
+pub async fn exchange_code(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    code: &str,
+    redirect_uri: &str,
+    code_verifier: &str,
+) -> Result<String, AppError> {
+    let body = token_request_body(config, code, redirect_uri, code_verifier)?;
+
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/x-www-form-urlencoded")?;
+    headers.set("Accept", "application/json")?;
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(JsValue::from_str(&body)));
+
+    let request = Request::new_with_init(&discovery.token_endpoint, &init)?;
+    let mut response = Fetch::Request(request).send().await?;
+    if !(200..300).contains(&response.status_code()) {
+        let error = response.text().await.unwrap_or_default();
+        return Err(provider_error(&format!(
+            "token endpoint answered {}: {error}",
+            response.status_code()
+        )));
+    }
+    let body = response
+        .text()
+        .await
+        .map_err(|_| provider_error("token endpoint sent no ID token"))?;
+
+    id_token_from_response(&body)
+}
+
+/// Checks the ID token's signature against the provider's keys, and its issuer, audience,
+/// lifetime and nonce.
+pub async fn verify_id_token(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    id_token: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, AppError> {
+    let keys: JwkSet = get_json(&discovery.jwks_uri).await?;
+    check_id_token(config, discovery, &keys, id_token, nonce)
+}
+
+/// [`verify_id_token`] with the provider's keys already fetched.
+pub fn check_id_token(
+    config: &OidcConfig,
+    discovery: &Discovery,
+    keys: &JwkSet,
+    id_token: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, AppError> {
+    let invalid = || AppError::BadRequest("Invalid ID token".to_string());
+
+    let header = decode_header(id_token).map_err(|_| invalid())?;
+    // Only the provider's public keys may sign, never a secret shared with us.
+    if matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        return Err(invalid());
+    }
+
+    let jwk = match &header.kid {
+        Some(kid) => keys.find(kid),
+        None => keys.keys.first(),
+    }
+    .ok_or_else(invalid)?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|_| invalid())?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&config.client_id]);
+    validation.set_issuer(&[&discovery.issuer]);
+    let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+        .map_err(|_| invalid())?
+        .claims;
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(invalid());
+    }
+
+    Ok(claims)
+}
@@ -0,0 +1,362 @@
+//! Rate limiting for the unauthenticated endpoints.
+//!
+//! Attempts are counted per key, e.g. `login-email:alice@example.com` or `login-ip:192.0.2.1`,
+//! in the `rate_limits` table so every isolate sees the same counts. Each key gets a number of
+//! free attempts per window. After that every further attempt locks the key out, for twice as
+//! long as the previous lockout, up to a cap.
+//!
+//! The policy itself is plain functions of the stored state and the current time, and the
+//! limiter reads the time from a [`Clock`], so both can be driven by a fake clock.
+
+use chrono::Utc;
+use serde::Deserialize;
+use worker::{query, D1Database};
+
+use crate::error::AppError;
+
+pub trait Clock {
+    /// Current Unix time in seconds.
+    fn now(&self) -> i64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        Utc::now().timestamp()
+    }
+}
+
+pub struct Policy {
+    /// Prefix of the keys this policy counts.
+    pub name: &'static str,
+    pub free_attempts: u32,
+    pub window_seconds: i64,
+    pub base_lockout_seconds: i64,
+    pub max_lockout_seconds: i64,
+}
+
+/// Wrong passwords or two-step codes for one account.
+pub const LOGIN_BY_EMAIL: Policy = Policy {
+    name: "login-email",
+    free_attempts: 5,
+    window_seconds: 60 * 60,
+    base_lockout_seconds: 30,
+    max_lockout_seconds: 15 * 60,
+};
+
+/// Failed logins from one address, across accounts. Looser, as addresses can be shared.
+pub const LOGIN_BY_IP: Policy = Policy {
+    name: "login-ip",
+    free_attempts: 20,
+    window_seconds: 60 * 60,
+    base_lockout_seconds: 30,
+    max_lockout_seconds: 15 * 60,
+};
+
+/// Any request to an endpoint that reveals whether an account exists.
+pub const LOOKUP_BY_IP: Policy = Policy {
+    name: "lookup-ip",
+    free_attempts: 60,
+    window_seconds: 60,
+    base_lockout_seconds: 60,
+    max_lockout_seconds: 15 * 60,
+};
+
+// How often an attempt is recomputed after parallel requests changed the key under it.
+const MAX_WRITE_TRIES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct AttemptState {
+    pub attempts: u32,
+    pub window_start: i64,
+    pub locked_until: i64,
+}
+
+/// Seconds until the key may try again, if it is locked out.
+pub fn retry_after(state: Option<&AttemptState>, now: i64) -> Option<i64> {
+    state
+        .map(|state| state.locked_until - now)
+        .filter(|&remaining| remaining > 0)
+}
+
+fn lockout_seconds(policy: &Policy, attempts: u32) -> i64 {
+    let over = attempts.saturating_sub(policy.free_attempts);
+    if over == 0 {
+        return 0;
+    }
+    // Past 2^20 the cap has long been reached, and the shift can't overflow.
+    let doublings = (over - 1).min(20);
+    (policy.base_lockout_seconds << doublings).min(policy.max_lockout_seconds)
+}
+
+/// Counts one more attempt against the key. Fails with the seconds to wait while the key is
+/// locked out, in which case the attempt isn't counted.
+pub fn attempt(
+    policy: &Policy,
+    state: Option<AttemptState>,
+    now: i64,
+) -> Result<AttemptState, i64> {
+    if let Some(seconds) = retry_after(state.as_ref(), now) {
+        return Err(seconds);
+    }
+    let (attempts, window_start) = match state {
+        Some(state) if now - state.window_start < policy.window_seconds => {
+            (state.attempts.saturating_add(1), state.window_start)
+        }
+        _ => (1, now),
+    };
+    let lockout = lockout_seconds(policy, attempts);
+
+    Ok(AttemptState {
+        attempts,
+        window_start,
+        locked_until: if lockout > 0 { now + lockout } else { 0 },
+    })
+}
+
+pub struct RateLimiter<'a, C: Clock> {
+    db: &'a D1Database,
+    clock: C,
+}
+
+impl<'a, C: Clock> RateLimiter<'a, C> {
+    pub fn new(db: &'a D1Database, clock: C) -> Self {
+        RateLimiter { db, clock }
+    }
+
+    /// Counts an attempt against the key, before the caller checks the credentials it carries,
+    /// and fails with [`AppError::TooManyRequests`] while the key is locked out.
+    ///
+    /// The next state comes from [`attempt`], and is only written if the row is still the one
+    /// it was computed from. A request that lost the race to a parallel one starts over from
+    /// the state that one wrote, so no attempt slips in uncounted.
+    pub async fn attempt(&self, policy: &Policy, subject: &str) -> Result<(), AppError> {
+        let key = format!("{}:{}", policy.name, subject);
+        let now = self.clock.now();
+
+        for _ in 0..MAX_WRITE_TRIES {
+            let state = self.load(&key).await?;
+            let next = attempt(policy, state, now).map_err(AppError::TooManyRequests)?;
+            if self.store(&key, state, next).await? {
+                return Ok(());
+            }
+        }
+        // Too busy a key to count the attempt, which is as good as locked.
+        Err(AppError::TooManyRequests(1))
+    }
+
+    /// Writes `next` over `previous`, unless another request changed the row in between.
+    async fn store(
+        &self,
+        key: &str,
+        previous: Option<AttemptState>,
+        next: AttemptState,
+    ) -> Result<bool, AppError> {
+        let statement = match previous {
+            None => query!(
+                self.db,
+                "INSERT INTO rate_limits (key, attempts, window_start, locked_until) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (key) DO NOTHING",
+                key,
+                next.attempts,
+                next.window_start,
+                next.locked_until
+            ),
+            Some(previous) => query!(
+                self.db,
+                "UPDATE rate_limits SET attempts = ?2, window_start = ?3, locked_until = ?4
+                 WHERE key = ?1 AND attempts = ?5 AND window_start = ?6 AND locked_until = ?7",
+                key,
+                next.attempts,
+                next.window_start,
+                next.locked_until,
+                previous.attempts,
+                previous.window_start,
+                previous.locked_until
+            ),
+        }
+        .map_err(|_| AppError::Database)?;
+        let result = statement.run().await?;
+
+        Ok(result.meta()?.and_then(|meta| meta.changes).unwrap_or(0) == 1)
+    }
+
+    async fn load(&self, key: &str) -> Result<Option<AttemptState>, AppError> {
+        query!(
+            self.db,
+            "SELECT attempts, window_start, locked_until FROM rate_limits WHERE key = ?1",
+            key
+        )
+        .map_err(|_| AppError::Database)?
+        .first(None)
+        .await
+        .map_err(|_| AppError::Database)
+    }
+
+    /// Takes back an attempt that turned out not to be a failure, e.g. a correct password or a
+    /// login that only got the two-step challenge.
+    pub async fn refund(&self, policy: &Policy, subject: &str) -> Result<(), AppError> {
+        let key = format!("{}:{}", policy.name, subject);
+        query!(
+            self.db,
+            "UPDATE rate_limits SET attempts = MAX(attempts - 1, 0) WHERE key = ?1",
+            key
+        )
+        .map_err(|_| AppError::Database)?
+        .run()
+        .await?;
+
+        Ok(())
+    }
+
+    /// Counts a login attempt against the account and the address it comes from.
+    pub async fn attempt_login(&self, email: &str, ip: &str) -> Result<(), AppError> {
+        self.attempt(&LOGIN_BY_IP, ip).await?;
+        self.attempt(&LOGIN_BY_EMAIL, email).await
+    }
+
+    /// Takes back a login attempt that got the two-step challenge.
+    pub async fn refund_login(&self, email: &str, ip: &str) -> Result<(), AppError> {
+        self.refund(&LOGIN_BY_EMAIL, email).await?;
+        self.refund(&LOGIN_BY_IP, ip).await
+    }
+
+    /// Forgets the account's failures after it logged in, and takes back the attempt.
+    pub async fn login_succeeded(&self, email: &str, ip: &str) -> Result<(), AppError> {
+        self.clear(&LOGIN_BY_EMAIL, email).await?;
+        self.refund(&LOGIN_BY_IP, ip).await
+    }
+
+    /// Forgets the key's attempts, e.g. after a successful login.
+    pub async fn clear(&self, policy: &Policy, subject: &str) -> Result<(), AppError> {
+        let key = format!("{}:{}", policy.name, subject);
+        query!(self.db, "DELETE FROM rate_limits WHERE key = ?1", key)
+            .map_err(|_| AppError::Database)?
+            .run()
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// The client address Cloudflare saw, which the client can't spoof.
+pub fn client_ip(headers: &axum::http::HeaderMap) -> String {
+    headers
+        .get("CF-Connecting-IP")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    struct FakeClock(Cell<i64>);
+
+    impl FakeClock {
+        fn advance(&self, seconds: i64) {
+            self.0.set(self.0.get() + seconds);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> i64 {
+            self.0.get()
+        }
+    }
+
+    const POLICY: Policy = Policy {
+        name: "test",
+        free_attempts: 3,
+        window_seconds: 600,
+        base_lockout_seconds: 30,
+        max_lockout_seconds: 100,
+    };
+
+    /// Counts an attempt the way the limiter stores it.
+    fn try_once(clock: &FakeClock, state: &mut Option<AttemptState>) -> Result<(), i64> {
+        *state = Some(attempt(&POLICY, *state, clock.now())?);
+        Ok(())
+    }
+
+    #[test]
+    fn free_attempts_are_not_locked_out() {
+        let clock = FakeClock(Cell::new(1_000));
+        let mut state = None;
+        for _ in 0..POLICY.free_attempts {
+            assert_eq!(try_once(&clock, &mut state), Ok(()));
+            assert_eq!(state.unwrap().locked_until, 0);
+        }
+        assert_eq!(state.unwrap().attempts, 3);
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_the_cap() {
+        let clock = FakeClock(Cell::new(1_000));
+        let mut state = None;
+        for _ in 0..POLICY.free_attempts {
+            try_once(&clock, &mut state).unwrap();
+        }
+
+        for expected in [30, 60, 100, 100] {
+            assert_eq!(try_once(&clock, &mut state), Ok(()));
+            assert_eq!(state.unwrap().locked_until, clock.now() + expected);
+            clock.advance(expected - 1);
+            assert_eq!(try_once(&clock, &mut state), Err(1));
+            clock.advance(1);
+        }
+    }
+
+    #[test]
+    fn locked_attempts_are_not_counted() {
+        let clock = FakeClock(Cell::new(1_000));
+        let mut state = None;
+        for _ in 0..=POLICY.free_attempts {
+            try_once(&clock, &mut state).unwrap();
+        }
+        let locked = state;
+
+        clock.advance(10);
+        assert_eq!(try_once(&clock, &mut state), Err(20));
+        assert_eq!(state, locked);
+    }
+
+    #[test]
+    fn window_resets_the_count() {
+        let clock = FakeClock(Cell::new(1_000));
+        let mut state = None;
+        for _ in 0..POLICY.free_attempts {
+            try_once(&clock, &mut state).unwrap();
+        }
+
+        clock.advance(POLICY.window_seconds - 1);
+        try_once(&clock, &mut state).unwrap();
+        assert_eq!(state.unwrap().attempts, 4);
+
+        clock.advance(POLICY.base_lockout_seconds);
+        try_once(&clock, &mut state).unwrap();
+        assert_eq!(
+            state,
+            Some(AttemptState {
+                attempts: 1,
+                window_start: clock.now(),
+                locked_until: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn retry_after_counts_down() {
+        let state = AttemptState {
+            attempts: 4,
+            window_start: 0,
+            locked_until: 50,
+        };
+        assert_eq!(retry_after(Some(&state), 20), Some(30));
+        assert_eq!(retry_after(Some(&state), 50), None);
+        assert_eq!(retry_after(None, 20), None);
+    }
+}
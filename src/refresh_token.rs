@@ -0,0 +1,163 @@
+//! Refresh tokens.
+//!
+//! A refresh token is an opaque random string. Only its SHA-256 hash is stored, in the
+//! `refresh_tokens` table, tied to the device that logged in. Every use of a token marks it as
+//! used and hands out a new one. Presenting a token that was already used means two parties
+//! hold it, so all of the device's tokens are revoked and both have to log in again.
+
+use base64::{engine::general_purpose, Engine as _};
+use chrono::{Duration, Utc};
+use ring::digest;
+use serde::Deserialize;
+use uuid::Uuid;
+use worker::{query, D1Database, D1PreparedStatement};
+
+use crate::error::AppError;
+
+const REFRESH_TOKEN_TTL_DAYS: i64 = 30;
+
+#[derive(Debug, Deserialize)]
+struct RefreshTokenRow {
+    user_id: String,
+    device_id: String,
+    client_id: String,
+    scope: String,
+    used: i32,
+    expires_at: i64,
+}
+
+/// The session a refresh token belongs to. Refreshing keeps the client and scopes the session
+/// was opened with, whatever the refresh request says.
+pub struct Session {
+    pub user_id: String,
+    pub device_id: String,
+    pub client_id: String,
+    pub scope: Vec<String>,
+}
+
+fn hash_token(token: &str) -> String {
+    general_purpose::STANDARD.encode(digest::digest(&digest::SHA256, token.as_bytes()))
+}
+
+/// Creates a refresh token for the session.
+pub async fn issue(db: &D1Database, session: &Session) -> Result<String, AppError> {
+    let token = general_purpose::URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+    let now = Utc::now();
+    let expires_at = (now + Duration::days(REFRESH_TOKEN_TTL_DAYS)).timestamp();
+
+    db.batch(vec![
+        // Used tokens are only kept around to detect reuse until they expire.
+        query!(
+            db,
+            "DELETE FROM refresh_tokens WHERE device_id = ?1 AND expires_at < ?2",
+            session.device_id,
+            now.timestamp()
+        )
+        .map_err(|_| AppError::Database)?,
+        query!(
+            db,
+            "INSERT INTO refresh_tokens (id, user_id, device_id, token_hash, client_id, scope, used, expires_at, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, 0, ?7, ?8)",
+            Uuid::new_v4().to_string(),
+            session.user_id,
+            session.device_id,
+            hash_token(&token),
+            session.client_id,
+            session.scope.join(" "),
+            expires_at,
+            now.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()
+        )
+        .map_err(|_| AppError::Database)?,
+    ])
+    .await?;
+
+    Ok(token)
+}
+
+/// The user a refresh token belongs to, without spending it, so a grant can turn the user away
+/// before the token is used up.
+pub async fn owner(db: &D1Database, token: &str) -> Result<Option<String>, AppError> {
+    let owner: Option<String> = query!(
+        db,
+        "SELECT user_id FROM refresh_tokens WHERE token_hash = ?1",
+        hash_token(token)
+    )
+    .map_err(|_| AppError::Database)?
+    .first(Some("user_id"))
+    .await
+    .map_err(|_| AppError::Database)?;
+
+    Ok(owner)
+}
+
+/// Spends a refresh token, returning the session it belongs to. The caller issues the
+/// replacement with [`issue`].
+pub async fn redeem(db: &D1Database, token: &str) -> Result<Session, AppError> {
+    let invalid = || AppError::Unauthorized("Invalid refresh token".to_string());
+    let token_hash = hash_token(token);
+
+    let row: RefreshTokenRow = query!(
+        db,
+        "SELECT user_id, device_id, client_id, scope, used, expires_at FROM refresh_tokens WHERE token_hash = ?1",
+        token_hash
+    )
+    .map_err(|_| AppError::Database)?
+    .first(None)
+    .await
+    .map_err(|_| AppError::Database)?
+    .ok_or_else(invalid)?;
+
+    if row.expires_at < Utc::now().timestamp() {
+        return Err(invalid());
+    }
+
+    // The conditional update settles two requests racing with the same token: only one of
+    // them gets to use it, the other counts as reuse.
+    let result = query!(
+        db,
+        "UPDATE refresh_tokens SET used = 1 WHERE token_hash = ?1 AND used = 0",
+        token_hash
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+    let changes = result.meta()?.and_then(|meta| meta.changes).unwrap_or(0);
+
+    if row.used != 0 || changes == 0 {
+        log::warn!(
+            "Refresh token reused for device {}, revoking its sessions",
+            row.device_id
+        );
+        revoke_device(db, &row.device_id)?.run().await?;
+        return Err(invalid());
+    }
+
+    Ok(Session {
+        user_id: row.user_id,
+        device_id: row.device_id,
+        client_id: row.client_id,
+        scope: row.scope.split_whitespace().map(str::to_string).collect(),
+    })
+}
+
+/// Revokes every refresh token of one device. Returned as a statement so it can be batched
+/// with the change that requires it.
+pub fn revoke_device(db: &D1Database, device_id: &str) -> Result<D1PreparedStatement, AppError> {
+    query!(db, "DELETE FROM refresh_tokens WHERE device_id = ?1", device_id)
+        .map_err(|_| AppError::Database)
+}
+
+/// Revokes the refresh tokens of the sessions the user opened with the personal API key.
+pub fn revoke_api_key(db: &D1Database, user_id: &str) -> Result<D1PreparedStatement, AppError> {
+    query!(
+        db,
+        "DELETE FROM refresh_tokens WHERE user_id = ?1 AND client_id LIKE 'user.%'",
+        user_id
+    )
+    .map_err(|_| AppError::Database)
+}
+
+/// Revokes the refresh tokens of all of the user's devices.
+pub fn revoke_user(db: &D1Database, user_id: &str) -> Result<D1PreparedStatement, AppError> {
+    query!(db, "DELETE FROM refresh_tokens WHERE user_id = ?1", user_id)
+        .map_err(|_| AppError::Database)
+}
@@ -1,28 +1,131 @@
 use axum::{
     routing::{get, post, put, delete},
-    Router,
+    Extension, Router,
 };
 use std::sync::Arc;
 use worker::Env;
 
-use crate::handlers::{accounts, ciphers, config, identity, sync, folders, import};
+use crate::auth::TokenRequirement;
+use crate::handlers::{accounts, admin, admin_panel, auth_requests, ciphers, config, identity, sso, sync, folders, import, two_factor};
 
 pub fn api_router(env: Env) -> Router {
     let app_state = Arc::new(env);
 
+    // Account security settings can't be changed with an API key session.
+    let interactive = Router::new()
+        .route("/api/accounts/api-key", post(accounts::api_key))
+        .route(
+            "/api/accounts/rotate-api-key",
+            post(accounts::rotate_api_key),
+        )
+        .route("/api/accounts/password", post(accounts::change_password))
+        .route("/api/accounts/kdf", post(accounts::change_kdf))
+        .route("/api/accounts/key", post(accounts::rotate_key))
+        .route(
+            "/api/accounts/email-token",
+            post(accounts::send_email_change_token),
+        )
+        .route("/api/accounts/email", post(accounts::change_email))
+        .route("/api/accounts", delete(accounts::delete_account))
+        .route("/api/accounts/delete", post(accounts::delete_account))
+        .route(
+            "/api/accounts/security-stamp",
+            post(accounts::rotate_security_stamp),
+        )
+        // Two-factor authentication
+        .route("/api/two-factor", get(two_factor::get_providers))
+        .route("/api/two-factor/disable", put(two_factor::disable))
+        .route(
+            "/api/two-factor/get-authenticator",
+            post(two_factor::authenticator::get_authenticator),
+        )
+        .route(
+            "/api/two-factor/authenticator",
+            post(two_factor::authenticator::activate_authenticator)
+                .put(two_factor::authenticator::activate_authenticator)
+                .delete(two_factor::authenticator::disable_authenticator),
+        )
+        .route("/api/two-factor/get-email", post(two_factor::email::get_email))
+        .route(
+            "/api/two-factor/send-email",
+            post(two_factor::email::send_email_setup),
+        )
+        .route(
+            "/api/two-factor/email",
+            put(two_factor::email::activate_email),
+        )
+        .route(
+            "/api/two-factor/get-webauthn",
+            post(two_factor::webauthn::get_webauthn),
+        )
+        .route(
+            "/api/two-factor/get-webauthn-challenge",
+            post(two_factor::webauthn::generate_webauthn_challenge),
+        )
+        .route(
+            "/api/two-factor/webauthn",
+            post(two_factor::webauthn::activate_webauthn)
+                .put(two_factor::webauthn::activate_webauthn)
+                .delete(two_factor::webauthn::delete_webauthn),
+        )
+        .route(
+            "/api/two-factor/get-recover",
+            post(two_factor::recover::get_recover),
+        )
+        .route("/api/devices/revoke", post(crate::handlers::devices::revoke_all))
+        .route("/api/devices/{id}/revoke", post(crate::handlers::devices::revoke_device))
+        .route(
+            "/api/devices/{id}/deactivate",
+            post(crate::handlers::devices::deactivate_device),
+        )
+        .route(
+            "/api/auth-requests/{id}",
+            put(auth_requests::update_auth_request),
+        )
+        .route_layer(Extension(TokenRequirement::INTERACTIVE));
+
     Router::new()
         // Identity/Auth routes
         .route("/api/accounts/revision-date", get(accounts::get_revision_date))
+        .route(
+            "/api/accounts/profile",
+            get(accounts::get_profile)
+                .put(accounts::update_profile)
+                .post(accounts::update_profile),
+        )
+        .route(
+            "/api/accounts/avatar",
+            put(accounts::update_avatar).post(accounts::update_avatar),
+        )
+        .route("/api/accounts/delete-recover", post(accounts::delete_recover))
+        .route(
+            "/api/accounts/delete-recover-token",
+            post(accounts::delete_recover_token),
+        )
         .route("/identity/accounts/prelogin", post(accounts::prelogin))
         .route(
             "/identity/accounts/register/finish",
             post(accounts::register),
         )
         .route("/identity/connect/token", post(identity::token))
+        .route("/identity/.well-known/jwks", get(identity::jwks))
+        // Single sign-on
+        .route("/identity/sso/prevalidate", get(sso::prevalidate))
+        .route("/identity/connect/authorize", get(sso::authorize))
+        .route("/sso/callback", get(sso::callback))
         .route(
             "/identity/accounts/register/send-verification-email",
             post(accounts::send_verification_email),
         )
+        .route(
+            "/identity/accounts/register/verification-email-clicked",
+            post(accounts::verification_email_clicked),
+        )
+        .route("/api/accounts/verify-email", post(accounts::verify_email))
+        .route(
+            "/api/accounts/verify-email-token",
+            post(accounts::verify_email_token),
+        )
         // Main data sync route
         .route("/api/sync", get(sync::get_sync_data))
         // Ciphers CRUD
@@ -36,8 +139,43 @@ pub fn api_router(env: Env) -> Router {
         .route("/api/folders/{id}", put(folders::update_folder))
         .route("/api/folders/{id}", delete(folders::delete_folder))
         .route("/api/config", get(config::config))
+        .route(
+            "/api/two-factor/send-email-login",
+            post(two_factor::email::send_email_login),
+        )
+        .route("/api/two-factor/recover", post(two_factor::recover::recover))
+        // Login with device
+        .route(
+            "/api/auth-requests",
+            get(auth_requests::get_auth_requests).post(auth_requests::create_auth_request),
+        )
+        .route(
+            "/api/auth-requests/{id}/response",
+            get(auth_requests::get_auth_request_response),
+        )
         // Devices
         .route("/api/devices/knowndevice", get(crate::handlers::devices::get_known_device))
+        .route("/api/devices", get(crate::handlers::devices::get_devices))
+        .route(
+            "/api/devices/identifier/{id}",
+            get(crate::handlers::devices::get_device_by_identifier),
+        )
+        .route("/api/devices/{id}", put(crate::handlers::devices::update_device))
         .route("/api/devices/identifier/{id}/token", put(crate::handlers::devices::put_token))
+        // Admin
+        .route("/admin", get(admin_panel::index))
+        .route("/admin/login", post(admin_panel::login))
+        .route("/admin/logout", post(admin_panel::logout))
+        .route("/admin/users", get(admin::get_users))
+        .route("/admin/users/{id}", delete(admin::delete_user))
+        .route("/admin/users/{id}/disable", post(admin::disable_user))
+        .route("/admin/users/{id}/enable", post(admin::enable_user))
+        .route("/admin/users/{id}/deauthorize", post(admin::deauthorize_user))
+        .route(
+            "/admin/invitations",
+            get(admin::get_invitations).post(admin::create_invitation),
+        )
+        .route("/admin/invitations/{id}", delete(admin::revoke_invitation))
+        .merge(interactive)
         .with_state(app_state)
 }
@@ -0,0 +1,278 @@
+//! Who may register.
+//!
+//! Registration is closed unless the email matches a rule of the `SIGNUP_ALLOWLIST` variable,
+//! or comes with an invitation. Rules are comma separated, either an address or `*@domain`.
+//! `SIGNUP_MAX_USERS` caps the number of accounts, invited or not. The `ALLOWED_EMAILS` secret
+//! of older deployments is read as the allowlist when `SIGNUP_ALLOWLIST` isn't set.
+//!
+//! Invitations are issued by the admin for one address. The invitee gets a signed token, which
+//! registration accepts once and only until it expires. Revoking the invitation voids the token.
+
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+use uuid::Uuid;
+use worker::{query, D1Database, Env};
+
+use crate::{auth, error::AppError, models::invitation::Invitation};
+
+const INVITATION_ACTION: &str = "invite";
+const INVITATION_TTL_DAYS: i64 = 7;
+
+#[derive(Deserialize)]
+struct CountRow {
+    count: i64,
+}
+
+pub fn not_allowed() -> AppError {
+    AppError::Unauthorized("Not allowed to signup".to_string())
+}
+
+fn allowlist(env: &Env) -> Vec<String> {
+    parse_allowlist(
+        env.var("SIGNUP_ALLOWLIST")
+            .ok()
+            .map(|rules| rules.to_string()),
+        env.secret("ALLOWED_EMAILS")
+            .ok()
+            .map(|rules| rules.to_string()),
+    )
+}
+
+/// The rules of `SIGNUP_ALLOWLIST`, or of the older `ALLOWED_EMAILS` when it isn't set.
+fn parse_allowlist(
+    signup_allowlist: Option<String>,
+    allowed_emails: Option<String>,
+) -> Vec<String> {
+    signup_allowlist
+        .or(allowed_emails)
+        .unwrap_or_default()
+        .split(',')
+        .map(|rule| rule.trim().to_lowercase())
+        .filter(|rule| !rule.is_empty())
+        .collect()
+}
+
+fn matches_rule(rule: &str, email: &str) -> bool {
+    match rule.strip_prefix("*@") {
+        Some(domain) => email
+            .rsplit_once('@')
+            .is_some_and(|(_, email_domain)| email_domain == domain),
+        None => rule == email,
+    }
+}
+
+/// The `SIGNUP_MAX_USERS` cap. Registration checks it again as it inserts the account, so
+/// parallel signups can't overshoot it.
+pub fn max_users(env: &Env) -> Option<i64> {
+    env.var("SIGNUP_MAX_USERS")
+        .ok()
+        .and_then(|max| max.to_string().parse().ok())
+}
+
+async fn has_room(env: &Env, db: &D1Database) -> Result<bool, AppError> {
+    let Some(max) = max_users(env) else {
+        return Ok(true);
+    };
+    let row: Option<CountRow> = query!(db, "SELECT COUNT(*) AS count FROM users")
+        .first(None)
+        .await
+        .map_err(|_| AppError::Database)?;
+
+    Ok(row.map_or(0, |row| row.count) < max)
+}
+
+/// Whether anyone may register without an invitation, for the clients to show the signup link.
+pub async fn is_open(env: &Env, db: &D1Database) -> Result<bool, AppError> {
+    Ok(!allowlist(env).is_empty() && has_room(env, db).await?)
+}
+
+/// Checks that `email` may register, with the invitation token if one was sent. Returns the
+/// invitation, for the caller to [`spend`] before creating the account.
+pub async fn check(
+    env: &Env,
+    db: &D1Database,
+    email: &str,
+    invitation_token: Option<&str>,
+) -> Result<Option<Invitation>, AppError> {
+    let email = email.to_lowercase();
+
+    if !has_room(env, db).await? {
+        return Err(not_allowed());
+    }
+
+    let Some(token) = invitation_token else {
+        return if allowlist(env).iter().any(|rule| matches_rule(rule, &email)) {
+            Ok(None)
+        } else {
+            Err(not_allowed())
+        };
+    };
+
+    let id = auth::decode_action_token(env, token, INVITATION_ACTION)?;
+    let invitation: Invitation = query!(db, "SELECT * FROM invitations WHERE id = ?1", id)
+        .map_err(|_| AppError::Database)?
+        .first(None)
+        .await
+        .map_err(|_| AppError::Database)?
+        .ok_or_else(not_allowed)?;
+    if !is_usable(&invitation, &email, Utc::now().timestamp()) {
+        return Err(not_allowed());
+    }
+
+    Ok(Some(invitation))
+}
+
+/// Whether the invitation lets `email` register at `now`.
+fn is_usable(invitation: &Invitation, email: &str, now: i64) -> bool {
+    invitation.email == email && invitation.used_at.is_none() && invitation.expires_at >= now
+}
+
+/// Marks the invitation used. Conditional, so of two registrations racing with one invitation
+/// only the first gets it.
+pub async fn spend(db: &D1Database, invitation: &Invitation) -> Result<(), AppError> {
+    let result = query!(
+        db,
+        "UPDATE invitations SET used_at = ?1 WHERE id = ?2 AND used_at IS NULL",
+        Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+        invitation.id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    if result.meta()?.and_then(|meta| meta.changes).unwrap_or(0) == 0 {
+        return Err(not_allowed());
+    }
+    Ok(())
+}
+
+/// Gives a spent invitation back, when the account it was spent on couldn't be created.
+pub async fn release(db: &D1Database, invitation: &Invitation) -> Result<(), AppError> {
+    query!(
+        db,
+        "UPDATE invitations SET used_at = NULL WHERE id = ?1",
+        invitation.id
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+/// Invites `email`, returning the invitation and its token.
+pub async fn invite(
+    env: &Env,
+    db: &D1Database,
+    email: &str,
+) -> Result<(Invitation, String), AppError> {
+    let now = Utc::now();
+    let valid_for = Duration::days(INVITATION_TTL_DAYS);
+    let invitation = Invitation {
+        id: Uuid::new_v4().to_string(),
+        email: email.to_lowercase(),
+        expires_at: (now + valid_for).timestamp(),
+        used_at: None,
+        created_at: now.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+    };
+    let token = auth::encode_action_token(env, &invitation.id, INVITATION_ACTION, valid_for)?;
+
+    query!(
+        db,
+        "INSERT INTO invitations (id, email, expires_at, created_at) VALUES (?1, ?2, ?3, ?4)",
+        invitation.id,
+        invitation.email,
+        invitation.expires_at,
+        invitation.created_at
+    )
+    .map_err(|_| AppError::Database)?
+    .run()
+    .await?;
+
+    Ok((invitation, token))
+}
+
+pub async fn list(db: &D1Database) -> Result<Vec<Invitation>, AppError> {
+    Ok(
+        query!(db, "SELECT * FROM invitations ORDER BY created_at DESC")
+            .all()
+            .await?
+            .results()?,
+    )
+}
+
+/// Voids an invitation. Returns whether there was one.
+pub async fn revoke(db: &D1Database, id: &str) -> Result<bool, AppError> {
+    let result = query!(db, "DELETE FROM invitations WHERE id = ?1", id)
+        .map_err(|_| AppError::Database)?
+        .run()
+        .await?;
+
+    Ok(result.meta()?.and_then(|meta| meta.changes).unwrap_or(0) > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn invitation() -> Invitation {
+        Invitation {
+            id: "invitation".to_string(),
+            email: "alice@example.com".to_string(),
+            expires_at: NOW + 60,
+            used_at: None,
+            created_at: "2023-11-14T22:13:20.000Z".to_string(),
+        }
+    }
+
+    #[test]
+    fn domain_rules_match_that_domain_only() {
+        let rules = parse_allowlist(Some("*@OurCo.com".to_string()), None);
+        let allowed = |email: &str| rules.iter().any(|rule| matches_rule(rule, email));
+
+        assert!(allowed("a@ourco.com"));
+        assert!(!allowed("a@sub.ourco.com"));
+        assert!(!allowed("a@ourco.com.evil"));
+        assert!(!allowed("a@evilourco.com"));
+        assert!(!allowed("ourco.com"));
+    }
+
+    #[test]
+    fn address_rules_match_that_address_only() {
+        let rules = parse_allowlist(Some(" Friend@Example.org , *@ourco.com,".to_string()), None);
+        assert_eq!(rules, ["friend@example.org", "*@ourco.com"]);
+
+        assert!(matches_rule(&rules[0], "friend@example.org"));
+        assert!(!matches_rule(&rules[0], "foe@example.org"));
+        assert!(!matches_rule(&rules[0], "friend@example.org.evil"));
+    }
+
+    #[test]
+    fn falls_back_to_allowed_emails() {
+        let old = Some("a@example.com".to_string());
+        assert_eq!(parse_allowlist(None, old.clone()), ["a@example.com"]);
+        assert_eq!(
+            parse_allowlist(Some("b@example.com".to_string()), old),
+            ["b@example.com"]
+        );
+        // Set but empty closes registration, whatever the old secret says.
+        assert!(parse_allowlist(Some(String::new()), Some("a@example.com".to_string())).is_empty());
+        assert!(parse_allowlist(None, None).is_empty());
+    }
+
+    #[test]
+    fn invitations_work_once_for_their_address_until_they_expire() {
+        assert!(is_usable(&invitation(), "alice@example.com", NOW));
+        assert!(is_usable(&invitation(), "alice@example.com", NOW + 60));
+        assert!(!is_usable(&invitation(), "alice@example.com", NOW + 61));
+        assert!(!is_usable(&invitation(), "bob@example.com", NOW));
+
+        let used = Invitation {
+            used_at: Some("2023-11-14T22:14:00.000Z".to_string()),
+            ..invitation()
+        };
+        assert!(!is_usable(&used, "alice@example.com", NOW));
+    }
+}